@@ -0,0 +1,68 @@
+//! Bus dispatch throughput: the page table against the linear device scan it
+//! replaced. Run with `cargo bench --bench bus`.
+
+use std::{cell::RefCell, hint::black_box, rc::Rc, time::Instant};
+
+use semr::{bus::{Bus, BusDevice}, clock::Clock, device::{ram::Ram, rom::Rom}};
+
+const ACCESSES: u32 = 20_000_000;
+
+/// The previous dispatch: scan every device on each access.
+struct LinearBus {
+    devices: Vec<Box<dyn BusDevice>>,
+}
+
+impl LinearBus {
+    fn read(&self, address: u16) -> u8 {
+        match self.devices.iter().find(|device| {
+            let start = device.get_base_address() as u32;
+            start <= address as u32 && (address as u32) < start + device.get_size()
+        }) {
+            Some(device) => device.read(address),
+            None => 0xFF
+        }
+    }
+}
+
+/// Spectrum-like layout: ROM, the screen RAM and the rest of RAM split in
+/// the 16K banks, with the hot accesses in the top bank.
+fn devices(clock: &Rc<RefCell<Clock>>) -> Vec<Box<dyn BusDevice>> {
+    vec![
+        Box::new(Rom::from_bytes(0x0000, 0x4000, &[0; 0x4000], Rc::clone(clock)).unwrap()),
+        Box::new(Ram::new(0x4000, 0x4000, Rc::clone(clock))),
+        Box::new(Ram::new(0x8000, 0x4000, Rc::clone(clock))),
+        Box::new(Ram::new(0xC000, 0x4000, Rc::clone(clock))),
+    ]
+}
+
+fn addresses() -> impl Iterator<Item = u16> {
+    (0..ACCESSES).map(|i| (i.wrapping_mul(40503) as u16) | 0x8000)
+}
+
+fn report(name: &str, start: Instant, checksum: u32) {
+    let seconds = start.elapsed().as_secs_f64();
+    println!(
+        "{:<12} {:>8.1} M accesses/s  ({:.3} s, checksum {})",
+        name,
+        ACCESSES as f64 / seconds / 1e6,
+        seconds,
+        checksum
+    );
+}
+
+fn main() {
+    let clock = Rc::new(RefCell::new(Clock::new()));
+
+    let linear = LinearBus { devices: devices(&clock) };
+    let start = Instant::now();
+    let checksum = addresses().fold(0_u32, |sum, address| sum + linear.read(black_box(address)) as u32);
+    report("linear scan", start, checksum);
+
+    let mut bus = Bus::new();
+    for device in devices(&clock) {
+        bus.add_device(device).unwrap();
+    }
+    let start = Instant::now();
+    let checksum = addresses().fold(0_u32, |sum, address| sum + bus.read(black_box(address)) as u32);
+    report("page table", start, checksum);
+}
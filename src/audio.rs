@@ -0,0 +1,64 @@
+/// Interleaved stereo samples for one frame. The frontend clears it before
+/// running a frame; every sound source then renders from the first sample
+/// on, adding its output to what other sources left there.
+pub struct AudioBuffer {
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl AudioBuffer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            samples: vec![],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Adds a stereo sample at frame position `index`.
+    pub fn mix(&mut self, index: usize, left: f32, right: f32) {
+        if self.samples.len() < (index + 1) * 2 {
+            self.samples.resize((index + 1) * 2, 0.0);
+        }
+        self.samples[index * 2] += left;
+        self.samples[index * 2 + 1] += right;
+    }
+
+    /// Stereo samples, left first.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod test_audio {
+    use super::AudioBuffer;
+
+    #[test]
+    fn test_mix() {
+        let mut buffer = AudioBuffer::new(44100);
+        buffer.mix(0, 0.25, 0.5);
+        buffer.mix(1, 0.1, 0.1);
+        buffer.mix(0, 0.25, 0.0);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.samples(), &[0.5, 0.5, 0.1, 0.1]);
+
+        buffer.clear();
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,46 +1,176 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::contention::Contention;
+
+/// A device mapped in the 64K memory space. It spans `get_size()` bytes from
+/// `get_base_address()`, so a single device may cover the whole space.
 pub trait BusDevice {
     fn read(&self, address: u16) -> u8 { self.peek(address) }
     fn write(&mut self, _address: u16, _value: u8) {}
     fn get_base_address(&self) -> u16;
-    fn get_size(&self) -> u16;
+    fn get_size(&self) -> u32;
     fn peek(&self, _address: u16) -> u8 { 0xFF }
     fn poke(&mut self, _address: u16, _value: u8) {}
     fn write_vec(&mut self, _address: u16, _data: Vec<u8>) {}
     fn read_word(&self, _address: u16) -> u16 { 0xFFFF }
+    /// Whether the ULA contends CPU accesses to `address`: 0x4000-0x7FFF on
+    /// every model.
+    fn is_contended(&self, address: u16) -> bool { (0x4000..0x8000).contains(&address) }
+    /// Whether a CPU write to `address` changes it; false for ROM.
+    fn is_writable(&self, _address: u16) -> bool { true }
+}
+
+/// A peripheral in the I/O space. Like the Spectrum hardware, devices decode
+/// the port address partially: a device answers every port for which
+/// `port & get_port_mask() == get_port_match()`.
+pub trait IoDevice {
+    fn get_port_mask(&self) -> u16;
+    fn get_port_match(&self) -> u16;
+    fn read_port(&mut self, _port: u16) -> u8 { 0xFF }
+    fn write_port(&mut self, _port: u16, _value: u8) {}
+}
+
+/// Notified before every memory write reaches its device, so devices that
+/// sample memory as time goes by (the ULA) can catch up first. Port writes
+/// are notified too, as they may page a different memory in.
+pub trait WriteObserver {
+    fn before_write(&mut self, address: u16);
+    fn before_port_write(&mut self, _port: u16) {}
+}
+
+/// A device shared between the memory map and the I/O space (or with the
+/// host) is added to the bus as an `Rc<RefCell<_>>`.
+impl<T: BusDevice> BusDevice for Rc<RefCell<T>> {
+    fn read(&self, address: u16) -> u8 { self.borrow().read(address) }
+    fn write(&mut self, address: u16, value: u8) { self.borrow_mut().write(address, value) }
+    fn get_base_address(&self) -> u16 { self.borrow().get_base_address() }
+    fn get_size(&self) -> u32 { self.borrow().get_size() }
+    fn peek(&self, address: u16) -> u8 { self.borrow().peek(address) }
+    fn poke(&mut self, address: u16, value: u8) { self.borrow_mut().poke(address, value) }
+    fn write_vec(&mut self, address: u16, data: Vec<u8>) { self.borrow_mut().write_vec(address, data) }
+    fn read_word(&self, address: u16) -> u16 { self.borrow().read_word(address) }
+    fn is_contended(&self, address: u16) -> bool { self.borrow().is_contended(address) }
+    fn is_writable(&self, address: u16) -> bool { self.borrow().is_writable(address) }
+}
+
+impl<T: WriteObserver> WriteObserver for Rc<RefCell<T>> {
+    fn before_write(&mut self, address: u16) { self.borrow_mut().before_write(address) }
+    fn before_port_write(&mut self, port: u16) { self.borrow_mut().before_port_write(port) }
+}
+
+impl<T: IoDevice> IoDevice for Rc<RefCell<T>> {
+    fn get_port_mask(&self) -> u16 { self.borrow().get_port_mask() }
+    fn get_port_match(&self) -> u16 { self.borrow().get_port_match() }
+    fn read_port(&mut self, port: u16) -> u8 { self.borrow_mut().read_port(port) }
+    fn write_port(&mut self, port: u16, value: u8) { self.borrow_mut().write_port(port, value) }
+}
+
+const PAGE_BITS: u32 = 8;
+const PAGE_COUNT: usize = 0x10000 >> PAGE_BITS;
+
+/// Owner of a 256-byte page of the address space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Page {
+    Empty,
+    Device(usize),
+    /// Shared by several devices or only partially mapped: resolved by scanning.
+    Mixed,
 }
 
 pub struct Bus {
     devices: Vec<Box<dyn BusDevice>>,
+    io_devices: Vec<Box<dyn IoDevice>>,
+    observers: Vec<Box<dyn WriteObserver>>,
+    contention: Option<Contention>,
+    pages: [Page; PAGE_COUNT],
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
     pub fn new() -> Self {
         Self {
             devices: vec![],
+            io_devices: vec![],
+            observers: vec![],
+            contention: None,
+            pages: [Page::Empty; PAGE_COUNT],
         }
     }
 
     pub fn add_device(&mut self, device: Box<dyn BusDevice>) -> Result<(), String> {
-        match self.is_available(device.get_base_address(), device.get_size()) {
-            true => {
-                self.devices.push(device);        
-                Ok(())
-            }
-            false => Err(format!("Component already exists at address {}", device.get_base_address())),
+        let (start, end) = range(device.as_ref());
+        if device.get_size() == 0 || end > 0x10000 {
+            return Err(format!("Device range {} does not fit in the address space", format_range(start, end)));
+        }
+
+        if let Some(other) = self.devices.iter().find(|other| {
+            let (other_start, other_end) = range(other.as_ref());
+            start < other_end && other_start < end
+        }) {
+            let (other_start, other_end) = range(other.as_ref());
+            return Err(format!(
+                "Device range {} overlaps device at {}",
+                format_range(start, end),
+                format_range(other_start, other_end)
+            ));
         }
+
+        self.devices.push(device);
+        self.map_pages(self.devices.len() - 1, start, end);
+        Ok(())
+    }
+
+    /// Enables contended memory timing: accesses to contended addresses are
+    /// delayed before reaching their device.
+    pub fn set_contention(&mut self, contention: Contention) {
+        self.contention = Some(contention);
+    }
+
+    pub fn contention(&self) -> Option<&Contention> {
+        self.contention.as_ref()
+    }
+
+    pub fn is_contended(&self, address: u16) -> bool {
+        self.find_device(address).is_some_and(|device| device.is_contended(address))
     }
 
     pub fn read(&self, address: u16) -> u8 {
         match self.find_device(address) {
-            Some(device) => device.read(address),
+            Some(device) => {
+                self.contend(device, address);
+                device.read(address)
+            }
             None => 0xFF
         }
     }
     
     pub fn write(&mut self, address: u16, value: u8) {
-        match self.find_mut_device(address) {
-            Some(device) => device.write(address, value),
-            None => (),
+        if let Some(index) = self.device_index(address) {
+            self.contend(self.devices[index].as_ref(), address);
+        }
+        for observer in self.observers.iter_mut() {
+            observer.before_write(address);
+        }
+        if let Some(device) = self.find_mut_device(address) {
+            device.write(address, value);
+        }
+    }
+
+    /// Writes like the CPU does, observers notified and ROM left alone, but
+    /// without taking any time: for traps standing in for ROM routines.
+    pub fn store(&mut self, address: u16, value: u8) {
+        for observer in self.observers.iter_mut() {
+            observer.before_write(address);
+        }
+        if let Some(device) = self.find_mut_device(address) {
+            if device.is_writable(address) {
+                device.poke(address, value);
+            }
         }
     }
 
@@ -52,73 +182,118 @@ impl Bus {
     }
 
     pub fn write_vec(&mut self, address: u16, data: Vec<u8>) {
-        match self.find_mut_device(address) {
-            Some(device) => device.write_vec(address, data),
-            None => ()
+        if let Some(device) = self.find_mut_device(address) {
+            device.write_vec(address, data);
         }
     }
     
+    /// Reads a little-endian word. Each byte is routed on its own, so a word
+    /// at 0xFFFF wraps to 0x0000 even across devices.
     pub fn read_word(&self, address: u16) -> u16 {
-        match self.find_device(address) {
-            Some(device) => device.read_word(address),
-            None => 0xFFFF
-        }   
+        let low = self.read(address) as u16;
+        let high = self.read(address.wrapping_add(1)) as u16;
+        high << 8 | low
     }
 
-    fn find_device(&self, address: u16) -> Option<&Box<dyn BusDevice>> {
-        for device in &self.devices {
-            if device.get_base_address() <= address && address < device.get_base_address() + device.get_size() {
-                return Some(device);
-            }
-        }
+    pub fn add_io_device(&mut self, device: Box<dyn IoDevice>) {
+        self.io_devices.push(device);
+    }
 
-        None
+    pub fn add_write_observer(&mut self, observer: Box<dyn WriteObserver>) {
+        self.observers.push(observer);
     }
 
-    fn find_mut_device(&mut self, address: u16) -> Option<&mut Box<dyn BusDevice>> {
-        for device in &mut self.devices {
-            if device.get_base_address() <= address && address < device.get_base_address() + device.get_size() {
-                return Some(device);
-            }
+    /// Reads a port. Every device decoding the address drives the data bus,
+    /// which ends up holding the AND of their values; with nobody answering
+    /// the bus floats high.
+    pub fn read_port(&mut self, port: u16) -> u8 {
+        self.io_devices
+            .iter_mut()
+            .filter(|device| port & device.get_port_mask() == device.get_port_match())
+            .fold(0xFF, |value, device| value & device.read_port(port))
+    }
+
+    pub fn write_port(&mut self, port: u16, value: u8) {
+        for observer in self.observers.iter_mut() {
+            observer.before_port_write(port);
         }
+        self.io_devices
+            .iter_mut()
+            .filter(|device| port & device.get_port_mask() == device.get_port_match())
+            .for_each(|device| device.write_port(port, value));
+    }
 
-        None
+    fn contend(&self, device: &dyn BusDevice, address: u16) {
+        if let Some(contention) = &self.contention {
+            if device.is_contended(address) {
+                contention.contend();
+            }
+        }
     }
 
-    fn is_available(&self, address: u16, size: u16) -> bool {
-        if self.devices.is_empty() {
-            return true;
+    fn map_pages(&mut self, index: usize, start: u32, end: u32) {
+        for page in (start >> PAGE_BITS)..=((end - 1) >> PAGE_BITS) {
+            let page_start = page << PAGE_BITS;
+            let page_end = page_start + (1 << PAGE_BITS);
+            let covered = start <= page_start && page_end <= end;
+
+            self.pages[page as usize] = match self.pages[page as usize] {
+                Page::Empty if covered => Page::Device(index),
+                _ => Page::Mixed,
+            };
         }
+    }
 
-        for device in &self.devices {
-            if
-                (device.get_base_address() <= address && address < device.get_base_address() + device.get_size()) ||
-                (device.get_base_address() <= address + size && address + size < device.get_base_address() + device.get_size()) ||
-                (address <= device.get_base_address() && device.get_base_address() < address + size) ||
-                (address <= device.get_base_address() + device.get_size() - 1 && device.get_base_address() + size < address + size) {
-                return false;
-            }
+    fn device_index(&self, address: u16) -> Option<usize> {
+        match self.pages[(address >> PAGE_BITS) as usize] {
+            Page::Empty => None,
+            Page::Device(index) => Some(index),
+            Page::Mixed => self.devices
+                .iter()
+                .position(|device| contains(device.as_ref(), address)),
         }
+    }
+
+    fn find_device(&self, address: u16) -> Option<&dyn BusDevice> {
+        self.device_index(address).map(|index| self.devices[index].as_ref())
+    }
 
-        true
+    fn find_mut_device(&mut self, address: u16) -> Option<&mut Box<dyn BusDevice>> {
+        self.device_index(address).map(|index| &mut self.devices[index])
     }
 }
 
+/// Half-open range `[start, end)` covered by a device. Computed in `u32` so a
+/// device ending at 0xFFFF doesn't overflow.
+fn range(device: &dyn BusDevice) -> (u32, u32) {
+    let start = device.get_base_address() as u32;
+    (start, start + device.get_size())
+}
+
+fn contains(device: &dyn BusDevice, address: u16) -> bool {
+    let (start, end) = range(device);
+    start <= address as u32 && (address as u32) < end
+}
+
+fn format_range(start: u32, end: u32) -> String {
+    format!("0x{:04X}-0x{:04X}", start, end.saturating_sub(1))
+}
+
 
 #[cfg(test)]
 mod test_bus {
     use std::{cell::RefCell, rc::Rc};
 
-    use crate::{clock::Clock, device::ram::Ram};
+    use crate::{clock::Clock, contention::Contention, device::{ram::Ram, rom::Rom}, model::Model};
 
-    use super::{Bus, BusDevice};
+    use super::{Bus, BusDevice, IoDevice, Page, WriteObserver};
 
     struct TestDevice {
         base_address: u16,
-        size: u16
+        size: u32
     }
     impl TestDevice {
-        fn new(base_address: u16, size: u16) -> Self {
+        fn new(base_address: u16, size: u32) -> Self {
             Self {
                 base_address,
                 size
@@ -130,11 +305,45 @@ mod test_bus {
             self.base_address
         }
 
-        fn get_size(&self) -> u16 {
+        fn get_size(&self) -> u32 {
             self.size
         }
     }
     
+    struct TestPort {
+        mask: u16,
+        port_match: u16,
+        value: u8,
+        written: Rc<RefCell<Vec<(u16, u8)>>>,
+    }
+    impl TestPort {
+        fn new(mask: u16, port_match: u16, value: u8) -> Self {
+            Self {
+                mask,
+                port_match,
+                value,
+                written: Rc::new(RefCell::new(vec![])),
+            }
+        }
+    }
+    impl IoDevice for TestPort {
+        fn get_port_mask(&self) -> u16 {
+            self.mask
+        }
+
+        fn get_port_match(&self) -> u16 {
+            self.port_match
+        }
+
+        fn read_port(&mut self, _port: u16) -> u8 {
+            self.value
+        }
+
+        fn write_port(&mut self, port: u16, value: u8) {
+            self.written.borrow_mut().push((port, value));
+        }
+    }
+
     #[test]
     fn test_add_device() {
         let mut bus = Bus::new();
@@ -145,8 +354,80 @@ mod test_bus {
         assert!(bus.add_device(Box::new(TestDevice::new(0x01FF, 0x100))).is_err());
         assert!(bus.add_device(Box::new(TestDevice::new(0x0300, 0x100))).is_ok());
         assert!(bus.add_device(Box::new(TestDevice::new(0x0200, 0x500))).is_err());
-        assert!(bus.add_device(Box::new(TestDevice::new(0x0200, 0x100))).is_err());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0200, 0x100))).is_ok());
         assert!(bus.add_device(Box::new(TestDevice::new(0x0200, 0x110))).is_err());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x1000, 0x0))).is_err());
+    }
+
+    #[test]
+    fn test_adjacent_devices() {
+        // ranges are half-open: a device may end right where the next one starts
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0300, 0x100))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0200, 0x100))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0400, 0x100))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x02FF, 0x1))).is_err());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0500, 0x1))).is_ok());
+    }
+
+    #[test]
+    fn test_page_table() {
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0000, 0x4000))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x4000, 0x80))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x4080, 0x100))).is_ok());
+
+        assert_eq!(bus.pages[0x00], Page::Device(0));
+        assert_eq!(bus.pages[0x3F], Page::Device(0));
+        assert_eq!(bus.pages[0x40], Page::Mixed);
+        assert_eq!(bus.pages[0x41], Page::Mixed);
+        assert_eq!(bus.pages[0x42], Page::Empty);
+
+        assert_eq!(bus.device_index(0x3FFF), Some(0));
+        assert_eq!(bus.device_index(0x407F), Some(1));
+        assert_eq!(bus.device_index(0x4080), Some(2));
+        assert_eq!(bus.device_index(0x417F), Some(2));
+        assert_eq!(bus.device_index(0x4180), None);
+        assert_eq!(bus.device_index(0xFFFF), None);
+    }
+
+    #[test]
+    fn test_top_of_memory() {
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(TestDevice::new(0xC000, 0x4000))).is_ok());
+        assert!(bus.add_device(Box::new(TestDevice::new(0xFFFF, 0x1))).is_err());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x8000, 0x8001))).is_err());
+        assert!(bus.add_device(Box::new(TestDevice::new(0x8000, 0x4000))).is_ok());
+
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(TestDevice::new(0x0000, 0x10000))).is_ok());
+        assert_eq!(
+            bus.add_device(Box::new(TestDevice::new(0xC000, 0x100))),
+            Err("Device range 0xC000-0xC0FF overlaps device at 0x0000-0xFFFF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_full_ram() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(Ram::new(0x0000, 0x10000, Rc::clone(&clock)))).is_ok());
+        bus.write(0xFFFF, 0x11);
+        bus.write(0x0000, 0x22);
+        assert_eq!(bus.read(0xFFFF), 0x11);
+        assert_eq!(bus.read(0x0000), 0x22);
+        assert_eq!(bus.read_word(0xFFFF), 0x2211);
+    }
+
+    #[test]
+    fn test_word_wraps_across_devices() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut bus = Bus::new();
+        assert!(bus.add_device(Box::new(Ram::new(0x0000, 0x4000, Rc::clone(&clock)))).is_ok());
+        assert!(bus.add_device(Box::new(Ram::new(0xC000, 0x4000, Rc::clone(&clock)))).is_ok());
+        bus.write(0xFFFF, 0x34);
+        bus.write(0x0000, 0x12);
+        assert_eq!(bus.read_word(0xFFFF), 0x1234);
     }
     
     #[test]
@@ -163,4 +444,99 @@ mod test_bus {
         assert_eq!(bus.read(0x0101), 0x00);
         assert_eq!(bus.read(0x1000), 0xFF);
     }
+
+    #[test]
+    fn test_io_routing() {
+        let mut bus = Bus::new();
+        assert_eq!(bus.read_port(0x00FE), 0xFF);
+
+        bus.add_io_device(Box::new(TestPort::new(0x0001, 0x0000, 0b10111111)));
+        bus.add_io_device(Box::new(TestPort::new(0x00FF, 0x00FE, 0b11100000)));
+        bus.add_io_device(Box::new(TestPort::new(0x8002, 0x0000, 0x12)));
+
+        assert_eq!(bus.read_port(0x7FFE), 0b10100000);
+        assert_eq!(bus.read_port(0xFEFE), 0b10100000);
+        assert_eq!(bus.read_port(0x00FC), 0x12 & 0b10111111);
+        assert_eq!(bus.read_port(0x7FFD), 0x12);
+        assert_eq!(bus.read_port(0xFFFF), 0xFF);
+    }
+
+    #[test]
+    fn test_contended_access() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut bus = Bus::new();
+        bus.add_device(Box::new(Ram::new(0x4000, 0xC000, Rc::clone(&clock)))).unwrap();
+        bus.set_contention(Contention::new(Model::Spectrum48K, Rc::clone(&clock)));
+
+        clock.borrow_mut().add(14335);
+        bus.read(0x8000);
+        assert_eq!(clock.borrow().read(), 14335 + 3);
+
+        clock.borrow_mut().reset();
+        clock.borrow_mut().add(14335);
+        bus.write(0x4000, 0x01);
+        assert_eq!(clock.borrow().read(), 14335 + 6 + 3);
+        bus.read(0x7FFF); // at 14344: delay 5
+        assert_eq!(clock.borrow().read(), 14344 + 5 + 3);
+    }
+
+    struct TestObserver {
+        seen: Rc<RefCell<Vec<u16>>>,
+    }
+    impl WriteObserver for TestObserver {
+        fn before_write(&mut self, address: u16) {
+            self.seen.borrow_mut().push(address);
+        }
+
+        fn before_port_write(&mut self, port: u16) {
+            self.seen.borrow_mut().push(port);
+        }
+    }
+
+    #[test]
+    fn test_write_observer() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut bus = Bus::new();
+        bus.add_device(Box::new(Ram::new(0x0000, 0x100, Rc::clone(&clock)))).unwrap();
+        bus.add_write_observer(Box::new(TestObserver { seen: Rc::clone(&seen) }));
+
+        bus.write(0x0010, 0x01);
+        bus.write(0x8000, 0x01);
+        bus.write_vec(0x0020, vec![0x01]);
+        bus.write_port(0x7FFD, 0x01);
+        bus.store(0x0030, 0x01);
+        assert_eq!(*seen.borrow(), vec![0x0010, 0x8000, 0x7FFD, 0x0030]);
+    }
+
+    #[test]
+    fn test_store() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut bus = Bus::new();
+        bus.add_device(Box::new(Rom::from_bytes(0x0000, 0x100, &[0x00; 0x100], Rc::clone(&clock)).unwrap())).unwrap();
+        bus.add_device(Box::new(Ram::new(0x0100, 0x100, Rc::clone(&clock)))).unwrap();
+
+        bus.store(0x00FF, 0x11);
+        bus.store(0x0100, 0x22);
+        assert_eq!(bus.peek(0x00FF), 0x00);
+        assert_eq!(bus.peek(0x0100), 0x22);
+        assert_eq!(clock.borrow().read(), 0);
+    }
+
+    #[test]
+    fn test_io_write() {
+        let mut bus = Bus::new();
+        let ula = TestPort::new(0x0001, 0x0000, 0xFF);
+        let ula_writes = Rc::clone(&ula.written);
+        let any = TestPort::new(0x0000, 0x0000, 0xFF);
+        let any_writes = Rc::clone(&any.written);
+        bus.add_io_device(Box::new(ula));
+        bus.add_io_device(Box::new(any));
+
+        bus.write_port(0x00FE, 0x07);
+        bus.write_port(0x00FF, 0x01);
+
+        assert_eq!(*ula_writes.borrow(), vec![(0x00FE, 0x07)]);
+        assert_eq!(*any_writes.borrow(), vec![(0x00FE, 0x07), (0x00FF, 0x01)]);
+    }
 }
\ No newline at end of file
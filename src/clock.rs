@@ -1,30 +1,130 @@
+use crate::model::Model;
+
+/// Something due at a given cycle, see `Clock::schedule`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Interrupt,
+    Scanline,
+    TapeEdge,
+}
+
+/// Counts T-states. Besides the running total it keeps the position within
+/// the current video frame, wrapping at the frame length, and a list of
+/// events scheduled at absolute cycle times.
 pub struct Clock {
-    tics: u32
+    tics: u64,
+    frame_length: u32,
+    frame_tics: u32,
+    frames: u64,
+    /// Pending events, kept sorted by due time.
+    events: Vec<(u64, Event)>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clock {
+    /// A clock framed like the 48K; see `set_frame_length`.
     pub fn new() -> Self {
         Clock {
             tics: 0,
+            frame_length: Model::Spectrum48K.t_states_per_frame(),
+            frame_tics: 0,
+            frames: 0,
+            events: vec![],
         }
     }
 
-    pub fn add(&mut self, tics:u32) {
-        self.tics += tics;
+    pub fn add(&mut self, tics: u32) {
+        self.tics += tics as u64;
+        let position = self.frame_tics as u64 + tics as u64;
+        let frame_length = self.frame_length as u64;
+        if position < frame_length {
+            self.frame_tics = position as u32;
+        } else {
+            self.frames += position / frame_length;
+            self.frame_tics = (position % frame_length) as u32;
+        }
     }
 
-    pub fn read(&self) -> u32 {
+    /// T-states elapsed since the last reset.
+    pub fn read(&self) -> u64 {
         self.tics
     }
 
+    /// T-states elapsed in the current frame.
+    pub fn frame_tics(&self) -> u32 {
+        self.frame_tics
+    }
+
+    /// Frames completed since the last reset.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn frame_length(&self) -> u32 {
+        self.frame_length
+    }
+
+    /// Changes the frame length. The frame count and the position in the
+    /// frame are recomputed from the total, as if every frame so far had the
+    /// new length. Panics on a zero length.
+    pub fn set_frame_length(&mut self, frame_length: u32) {
+        assert!(frame_length > 0, "Frame length must not be zero");
+        self.frame_length = frame_length;
+        self.frame_tics = (self.tics % frame_length as u64) as u32;
+        self.frames = self.tics / frame_length as u64;
+    }
+
+    /// Registers `event` as due at cycle `at`.
+    pub fn schedule(&mut self, at: u64, event: Event) {
+        let index = self.events.partition_point(|(due, _)| *due <= at);
+        self.events.insert(index, (at, event));
+    }
+
+    /// Removes every pending `event`.
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|(_, pending)| *pending != event);
+    }
+
+    /// Cycle at which the next `event` is due, if scheduled.
+    pub fn next_due(&self, event: Event) -> Option<u64> {
+        self.events
+            .iter()
+            .find(|(_, pending)| *pending == event)
+            .map(|(due, _)| *due)
+    }
+
+    /// Takes the earliest event that is due by now.
+    pub fn pop_due(&mut self) -> Option<(u64, Event)> {
+        match self.events.first() {
+            Some((due, _)) if *due <= self.tics => Some(self.events.remove(0)),
+            _ => None,
+        }
+    }
+
+    /// Takes the earliest `event` that is due by now, leaving other events.
+    pub fn pop_due_event(&mut self, event: Event) -> Option<u64> {
+        let index = self.events
+            .iter()
+            .position(|(due, pending)| *pending == event && *due <= self.tics)?;
+        Some(self.events.remove(index).0)
+    }
+
     pub fn reset(&mut self) {
         self.tics = 0;
+        self.frame_tics = 0;
+        self.frames = 0;
+        self.events.clear();
     }
 }
 
 #[cfg(test)]
 mod test_clock {
-    use super::Clock;
+    use super::{Clock, Event};
 
     #[test]
     fn add_and_read_tics() {
@@ -42,4 +142,59 @@ mod test_clock {
         clk.reset();
         assert_eq!(clk.read(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn no_overflow() {
+        let mut clk = Clock::new();
+        for _ in 0..2 {
+            clk.add(u32::MAX);
+        }
+        assert_eq!(clk.read(), 2 * u32::MAX as u64);
+        assert!(clk.frame_tics() < 69888);
+        assert_eq!(clk.frames(), 2 * u32::MAX as u64 / 69888);
+    }
+
+    #[test]
+    fn frame_wrap() {
+        let mut clk = Clock::new();
+        clk.add(69880);
+        assert_eq!(clk.frames(), 0);
+        clk.add(10);
+        assert_eq!(clk.frame_tics(), 2);
+        assert_eq!(clk.frames(), 1);
+
+        clk.set_frame_length(70908);
+        assert_eq!(clk.frame_tics(), 69890);
+        assert_eq!(clk.frames(), 0);
+
+        clk.set_frame_length(100);
+        assert_eq!(clk.frame_tics(), 90);
+        assert_eq!(clk.frames(), 698);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_frame_length() {
+        Clock::new().set_frame_length(0);
+    }
+
+    #[test]
+    fn events() {
+        let mut clk = Clock::new();
+        clk.schedule(100, Event::TapeEdge);
+        clk.schedule(50, Event::Scanline);
+        clk.schedule(100, Event::Interrupt);
+        assert_eq!(clk.next_due(Event::TapeEdge), Some(100));
+        assert_eq!(clk.pop_due(), None);
+
+        clk.add(100);
+        assert_eq!(clk.pop_due_event(Event::Interrupt), Some(100));
+        assert_eq!(clk.pop_due(), Some((50, Event::Scanline)));
+        assert_eq!(clk.pop_due(), Some((100, Event::TapeEdge)));
+        assert_eq!(clk.pop_due(), None);
+
+        clk.schedule(200, Event::TapeEdge);
+        clk.cancel(Event::TapeEdge);
+        assert_eq!(clk.next_due(Event::TapeEdge), None);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{cpu::RefClock, model::Model};
+
+/// Delays added to an access starting at each T-state of an 8 T-state
+/// ULA fetch cycle.
+const PATTERN: [u8; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+
+/// Contended memory timing. While the ULA fetches screen data it holds off
+/// CPU accesses to the contended memory, so an access gets delayed by the
+/// table entry of the frame T-state it starts at.
+pub struct Contention {
+    clock: RefClock,
+    model: Model,
+    table: Vec<u8>,
+}
+
+impl Contention {
+    pub fn new(model: Model, clock: RefClock) -> Self {
+        let mut table = vec![0; model.t_states_per_frame() as usize];
+        let start = model.first_contended_t_state();
+        for line in 0..192 {
+            let line_start = (start + line * model.t_states_per_line()) as usize;
+            for t_state in 0..128 {
+                table[line_start + t_state] = PATTERN[t_state % 8];
+            }
+        }
+
+        Self {
+            clock,
+            model,
+            table,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Delay for an access starting at a frame T-state.
+    pub fn delay(&self, t_state: u32) -> u32 {
+        self.table[t_state as usize % self.table.len()] as u32
+    }
+
+    /// Stalls the clock before an access to contended memory.
+    pub fn contend(&self) {
+        let delay = self.delay(self.clock.borrow().frame_tics());
+        self.clock.borrow_mut().add(delay);
+    }
+
+    /// Timing of an I/O cycle, 4 T-states stretched following the ULA rules:
+    /// a contended high byte gets its address contended like memory and even
+    /// ports (the ULA's) hold the cycle after its first T-state.
+    pub fn io_cycle(&self, port: u16, high_contended: bool) {
+        let ula_port = port & 0x0001 == 0;
+        let steps: &[(bool, u32)] = match (high_contended, ula_port) {
+            (false, false) => &[(false, 4)],                                  // N:4
+            (false, true) => &[(false, 1), (true, 3)],                        // N:1 C:3
+            (true, true) => &[(true, 1), (true, 3)],                          // C:1 C:3
+            (true, false) => &[(true, 1), (true, 1), (true, 1), (true, 1)],   // C:1 C:1 C:1 C:1
+        };
+
+        for (contended, t_states) in steps {
+            if *contended {
+                self.contend();
+            }
+            self.clock.borrow_mut().add(*t_states);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_contention {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{clock::Clock, model::Model};
+
+    use super::Contention;
+
+    #[test]
+    fn test_table() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let contention = Contention::new(Model::Spectrum48K, clock.clone());
+        assert_eq!(contention.delay(14334), 0);
+        assert_eq!(contention.delay(14335), 6);
+        assert_eq!(contention.delay(14336), 5);
+        assert_eq!(contention.delay(14341), 0);
+        assert_eq!(contention.delay(14342), 0);
+        assert_eq!(contention.delay(14343), 6);
+        assert_eq!(contention.delay(14335 + 127), 0);
+        assert_eq!(contention.delay(14335 + 128), 0);
+        assert_eq!(contention.delay(14335 + 224), 6);
+        assert_eq!(contention.delay(14335 + 191 * 224 + 8), 6);
+        assert_eq!(contention.delay(14335 + 192 * 224), 0);
+
+        let contention = Contention::new(Model::Spectrum128K, clock);
+        assert_eq!(contention.delay(14360), 0);
+        assert_eq!(contention.delay(14361), 6);
+        assert_eq!(contention.delay(14361 + 228), 6);
+    }
+
+    #[test]
+    fn test_io_cycle() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let contention = Contention::new(Model::Spectrum48K, clock.clone());
+
+        let cycle = |start: u32, port: u16, high_contended: bool| {
+            clock.borrow_mut().reset();
+            clock.borrow_mut().add(start);
+            contention.io_cycle(port, high_contended);
+            clock.borrow().read() as u32 - start
+        };
+
+        // outside the screen fetch every pattern takes 4 T-states
+        assert_eq!(cycle(0, 0x00FE, false), 4);
+        assert_eq!(cycle(0, 0x40FF, true), 4);
+
+        // N:1 C:3 starting at 14334, contended at 14335
+        assert_eq!(cycle(14334, 0x00FE, false), 10);
+        assert_eq!(cycle(14335, 0x00FF, false), 4);
+        // C:1 C:3: 6 + 1, then uncontended at 14342
+        assert_eq!(cycle(14335, 0x40FE, true), 10);
+        // C:1 C:1 C:1 C:1: 6 + 1, 0 + 1, 6 + 1, 0 + 1
+        assert_eq!(cycle(14335, 0x40FF, true), 16);
+    }
+}
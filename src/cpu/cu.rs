@@ -18,6 +18,7 @@ pub struct CUnit {
     pub status: Status,
     pub address_mode: Option<IndexedAddressMode>,
     pub prefix: Option<u8>,
+    pub int_delayed: bool,
 }
 
 impl CUnit {
@@ -29,95 +30,435 @@ impl CUnit {
             status: Status::Running,
             address_mode: None,
             prefix: None,
+            int_delayed: false,
         }
     }
 
+    /// Acknowledges a maskable interrupt with `data` on the data bus.
+    pub fn interrupt(&mut self, data: u8) -> Result<(), String> {
+        // IM 0 runs the byte on the bus as an opcode, but a prefix would need
+        // further bytes the bus never supplies.
+        if self.regs.im == 0 && matches!(data, 0xCB | 0xDD | 0xED | 0xFD) {
+            return Err(format!("Prefix {:#04X} on the data bus is not supported in IM 0", data));
+        }
+
+        self.status = Status::Running;
+        self.regs.increment_r();
+        self.regs.iff1 = false;
+        self.regs.iff2 = false;
+
+        match self.regs.im {
+            0 => {
+                // The acknowledge cycle takes 6 T-states and executes the byte
+                // on the bus; only single byte instructions (RST p) make sense.
+                self.clock.borrow_mut().add(6);
+                return self.decode(data);
+            }
+            1 => {
+                self.clock.borrow_mut().add(7);
+                self.push_word(self.regs.pc);
+                self.regs.pc = 0x0038;
+            }
+            _ => {
+                self.clock.borrow_mut().add(7);
+                self.push_word(self.regs.pc);
+                let vector = (self.regs.i as u16) << 8 | data as u16;
+                self.regs.pc = self.read_word(vector);
+            }
+        }
+
+        self.regs.wz = self.regs.pc;
+
+        Ok(())
+    }
+
+    /// Acknowledges a non maskable interrupt. IFF2 keeps the previous IFF1 so
+    /// RETN can restore it.
+    pub fn nmi(&mut self) {
+        self.status = Status::Running;
+        self.regs.increment_r();
+        self.regs.iff1 = false;
+
+        self.clock.borrow_mut().add(5);
+        self.push_word(self.regs.pc);
+        self.regs.pc = 0x0066;
+        self.regs.wz = self.regs.pc;
+    }
+
     fn get_address_by_address_mode(&mut self) -> u16 {
         match &self.address_mode {
             None => {
                 self.regs.main.hl()
             }
-            Some(mode) => {
-                let base_address = match mode {
-                    IndexedAddressMode::IX => self.regs.ix,
-                    IndexedAddressMode::IY => self.regs.iy
-                };
+            Some(_) => {
+                let d = self.fetch_byte();
+                self.internal(self.regs.pc.wrapping_sub(1), 5);
+                self.get_indexed_address(d)
+            }
+        }
+    }
 
-                let d = self.bus.borrow().read(self.regs.pc) as i8;
-                self.regs.pc += 1;
+    /// IX+d or IY+d, which also becomes the new MEMPTR.
+    fn get_indexed_address(&mut self, d: u8) -> u16 {
+        let address = self.get_index_hl().wrapping_add(d as i8 as u16);
+        self.regs.wz = address;
+        address
+    }
 
-                self.clock.borrow_mut().add(2);
-                base_address.wrapping_add(d as u16)
+    /// Internal T-states of an instruction. The address bus keeps `address`
+    /// meanwhile, so in contended memory each of them is delayed like an
+    /// access.
+    fn internal(&mut self, address: u16, t_states: u32) {
+        let bus = self.bus.borrow();
+        match bus.contention() {
+            Some(contention) if bus.is_contended(address) => {
+                for _ in 0..t_states {
+                    contention.contend();
+                    self.clock.borrow_mut().add(1);
+                }
             }
+            _ => self.clock.borrow_mut().add(t_states),
+        }
+    }
+
+    /// IR, left on the address bus by the refresh cycle of an opcode fetch.
+    fn ir(&self) -> u16 {
+        (self.regs.i as u16) << 8 | self.regs.r as u16
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let value = self.bus.borrow().read(self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte() as u16;
+        let high = self.fetch_byte() as u16;
+        high << 8 | low
+    }
+
+    fn read_word(&mut self, address: u16) -> u16 {
+        let low = self.bus.borrow().read(address) as u16;
+        let high = self.bus.borrow().read(address.wrapping_add(1)) as u16;
+        high << 8 | low
+    }
+
+    fn write_word(&mut self, address: u16, value: u16) {
+        self.bus.borrow_mut().write(address, value as u8);
+        self.bus.borrow_mut().write(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    fn push_word(&mut self, value: u16) {
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.bus.borrow_mut().write(self.regs.sp, (value >> 8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.bus.borrow_mut().write(self.regs.sp, value as u8);
+    }
+
+    fn pop_word(&mut self) -> u16 {
+        let value = self.read_word(self.regs.sp);
+        self.regs.sp = self.regs.sp.wrapping_add(2);
+        value
+    }
+
+    /// HL, or IX/IY when running under a DD/FD prefix.
+    fn get_index_hl(&self) -> u16 {
+        match self.address_mode {
+            None => self.regs.main.hl(),
+            Some(IndexedAddressMode::IX) => self.regs.ix,
+            Some(IndexedAddressMode::IY) => self.regs.iy,
+        }
+    }
+
+    fn set_index_hl(&mut self, value: u16) {
+        match self.address_mode {
+            None => self.regs.main.set_hl(value),
+            Some(IndexedAddressMode::IX) => self.regs.ix = value,
+            Some(IndexedAddressMode::IY) => self.regs.iy = value,
+        }
+    }
+
+    /// 8-bit register by opcode index. Under a DD/FD prefix H and L select
+    /// the halves of IX/IY (undocumented); forms that also address (IX+d)
+    /// keep using the real H and L through `regs.main` instead.
+    fn get_reg(&self, index: u8) -> Result<u8, String> {
+        match (index, &self.address_mode) {
+            (0b100, Some(_)) => Ok((self.get_index_hl() >> 8) as u8),
+            (0b101, Some(_)) => Ok(self.get_index_hl() as u8),
+            _ => self.regs.main.get_reg(index),
+        }
+    }
+
+    fn set_reg(&mut self, index: u8, value: u8) -> Result<(), String> {
+        let index_hl = self.get_index_hl();
+
+        match (index, &self.address_mode) {
+            (0b100, Some(_)) => self.set_index_hl((index_hl & 0x00FF) | (value as u16) << 8),
+            (0b101, Some(_)) => self.set_index_hl((index_hl & 0xFF00) | value as u16),
+            _ => return self.regs.main.set_reg(index, value),
+        }
+
+        Ok(())
+    }
+
+    /// Register pair selected by bits 4-5 of an opcode: BC, DE, HL (IX/IY) or SP.
+    fn get_rp(&self, index: u8) -> u16 {
+        match index {
+            0b00 => self.regs.main.bc(),
+            0b01 => self.regs.main.de(),
+            0b10 => self.get_index_hl(),
+            _ => self.regs.sp,
+        }
+    }
+
+    fn set_rp(&mut self, index: u8, value: u16) {
+        match index {
+            0b00 => self.regs.main.set_bc(value),
+            0b01 => self.regs.main.set_de(value),
+            0b10 => self.set_index_hl(value),
+            _ => self.regs.sp = value,
+        }
+    }
+
+    /// Register pair as encoded by PUSH/POP, where index 3 selects AF instead of SP.
+    fn get_rp2(&self, index: u8) -> u16 {
+        match index {
+            0b11 => self.regs.main.af(),
+            _ => self.get_rp(index),
+        }
+    }
+
+    fn set_rp2(&mut self, index: u8, value: u16) {
+        match index {
+            0b11 => self.regs.main.set_af(value),
+            _ => self.set_rp(index, value),
         }
     }
 
     pub fn decode(&mut self, opcode: u8) -> Result<(), String> {
         match self.prefix {
-            None => self.decode_prefix_none(opcode),
-            Some(0xDD) => self.decode_prefix_none(opcode),
-            Some(0xFD) => self.decode_prefix_none(opcode),
+            None | Some(0xDD) | Some(0xFD) => self.decode_prefix_none(opcode),
+            Some(0xCB) => self.decode_prefix_cb(opcode),
             Some(0xED) => self.decode_prefix_ed(opcode),
             Some(prefix) => Err(format!("Prefix {:#04X} not implemented", prefix)),
         }
     }
 
-    fn decode_prefix_none(&mut self, opcode: u8) -> Result<(), String> {
-        let reset_prefix = self.prefix.is_some();
-
-        if opcode & 0b11000111 == 0b110 {
-            if self.ld_r_n(opcode).is_ok() { return Ok(()) }
-        }
+    fn set_prefix(&mut self, prefix: u8, address_mode: Option<IndexedAddressMode>) {
+        self.prefix = Some(prefix);
+        self.address_mode = address_mode;
+    }
 
+    fn decode_prefix_none(&mut self, opcode: u8) -> Result<(), String> {
         match opcode {
             0x00 => self.nop(),
+            0x01 | 0x11 | 0x21 | 0x31 => self.ld_rr_nn(opcode),
             0x02 => self.ld_bc_a(),
+            0x07 | 0x0F | 0x17 | 0x1F => self.rotate_a(opcode),
+            0x08 => self.ex_af_af(),
+            0x03 | 0x13 | 0x23 | 0x33 => self.inc_rr(opcode),
+            0x09 | 0x19 | 0x29 | 0x39 => self.add_hl_rr(opcode),
+            0x0B | 0x1B | 0x2B | 0x3B => self.dec_rr(opcode),
+            0x10 => self.djnz(),
+            0x18 => self.jr(),
+            0x20 | 0x28 | 0x30 | 0x38 => self.jr_cc(opcode),
             0x0A => self.ld_a_bc(),
             0x12 => self.ld_de_a(),
             0x1A => self.ld_a_de(),
+            0x22 => self.ld_nn_hl(),
+            0x27 => self.daa(),
+            0x2A => self.ld_hl_nn(),
+            0x2F => self.cpl(),
             0x32 => self.ld_nn_a(),
             0x36 => self.ld_hl_n(),
+            0x37 => self.scf(),
             0x3A => self.ld_a_nn(),
-            0x40..=0x7F =>{
-                if self.halt(opcode).is_err() {
-                    if self.ld_r_r(opcode).is_err() {
-                        if self.ld_r_hl(opcode).is_err() {
-                            if self.ld_hl_r(opcode).is_err() {
-                                return Err(format!("Opcode {:#04X} not implemented", opcode))
-                            }
-                        }
-                    }
-                }
+            0x3F => self.ccf(),
+            op if op & 0b11000111 == 0b00000100 => self.inc_r(op)?,
+            op if op & 0b11000111 == 0b00000101 => self.dec_r(op)?,
+            op if op & 0b11000111 == 0b00000110 => self.ld_r_n(op)?,
+            0x76 => self.halt(),
+            0x70..=0x77 => self.ld_hl_r(opcode)?,
+            op if op & 0b11000111 == 0b01000110 => self.ld_r_hl(op)?,
+            0x40..=0x7F => self.ld_r_r(opcode)?,
+            0x80..=0xBF => self.alu_r(opcode)?,
+            op if op & 0b11000111 == 0b11000000 => self.ret_cc(op),
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => self.pop_qq(opcode),
+            op if op & 0b11000111 == 0b11000010 => self.jp_cc(op),
+            0xC3 => self.jp(),
+            op if op & 0b11000111 == 0b11000100 => self.call_cc(op),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => self.push_qq(opcode),
+            op if op & 0b11000111 == 0b11000110 => self.alu_n(op),
+            op if op & 0b11000111 == 0b11000111 => self.rst(op),
+            0xC9 => self.ret(),
+            0xCB if self.address_mode.is_some() => {
+                self.decode_prefix_indexed_cb()?;
+            }
+            0xCB => {
+                self.set_prefix(opcode, None);
+                return Ok(());
             }
+            0xCD => self.call(),
+            0xD3 => self.out_n_a(),
+            0xD9 => self.exx(),
+            0xDB => self.in_a_n(),
+            0xE3 => self.ex_sp_hl(),
+            0xE9 => self.jp_hl(),
+            0xEB => self.ex_de_hl(),
+            0xF3 => self.di(),
+            0xF9 => self.ld_sp_hl(),
+            0xFB => self.ei(),
             0xDD => {
-                self.prefix = Some(opcode);
-                self.address_mode = Some(IndexedAddressMode::IX);
-                self.clock.borrow_mut().add(1);
+                self.set_prefix(opcode, Some(IndexedAddressMode::IX));
+                return Ok(());
             }
             0xED => {
-                self.prefix = Some(opcode);
-                self.clock.borrow_mut().add(1);
+                self.set_prefix(opcode, None);
+                return Ok(());
             }
             0xFD => {
-                self.prefix = Some(opcode);
-                self.address_mode = Some(IndexedAddressMode::IY);
-                self.clock.borrow_mut().add(1);
+                self.set_prefix(opcode, Some(IndexedAddressMode::IY));
+                return Ok(());
             }
             _ => return Err(format!("Opcode {:#04X} not implemented", opcode))
         }
 
-        if reset_prefix {
-            self.address_mode = None;
-            self.prefix = None;
+        self.address_mode = None;
+        self.prefix = None;
+
+        Ok(())
+    }
+
+    fn decode_prefix_cb(&mut self, opcode: u8) -> Result<(), String> {
+        let operation = (opcode & 0b11000000) >> 6;
+        let bit = (opcode & 0b00111000) >> 3;
+        let src = opcode & 0b00000111;
+
+        if src == 0b110 {
+            let address = self.regs.main.hl();
+            let value = self.bus.borrow().read(address);
+            self.internal(address, 1);
+
+            if operation == 0b01 {
+                self.bit(bit, value, (self.regs.wz >> 8) as u8);
+            } else {
+                let result = self.cb_operation(opcode, value);
+                self.bus.borrow_mut().write(address, result);
+            }
+        } else {
+            let value = self.regs.main.get_reg(src)?;
+
+            if operation == 0b01 {
+                self.bit(bit, value, value);
+            } else {
+                let result = self.cb_operation(opcode, value);
+                self.regs.main.set_reg(src, result)?;
+            }
         }
 
+        self.prefix = None;
+
         Ok(())
     }
 
+    /// DDCB/FDCB opcodes: the displacement comes before the opcode, and every
+    /// form operates on (IX+d)/(IY+d). Register encodings other than 0b110
+    /// also copy the result into that register (undocumented).
+    fn decode_prefix_indexed_cb(&mut self) -> Result<(), String> {
+        let d = self.fetch_byte();
+        let opcode = self.fetch_byte();
+        self.internal(self.regs.pc.wrapping_sub(1), 2);
+
+        let address = self.get_indexed_address(d);
+        let value = self.bus.borrow().read(address);
+        self.internal(address, 1);
+
+        if (opcode & 0b11000000) >> 6 == 0b01 {
+            self.bit((opcode & 0b00111000) >> 3, value, (address >> 8) as u8);
+        } else {
+            let result = self.cb_operation(opcode, value);
+            self.bus.borrow_mut().write(address, result);
+
+            let dst = opcode & 0b00000111;
+            if dst != 0b110 {
+                self.regs.main.set_reg(dst, result)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rotate/shift, RES or SET as selected by a CB opcode. BIT is handled apart
+    /// because it does not write back.
+    fn cb_operation(&mut self, opcode: u8, value: u8) -> u8 {
+        let bit = (opcode & 0b00111000) >> 3;
+
+        match (opcode & 0b11000000) >> 6 {
+            0b00 => self.rotate_shift(bit, value),
+            0b10 => value & !(1 << bit),
+            _ => value | (1 << bit),
+        }
+    }
+
+    /// RLC, RRC, RL, RR, SLA, SRA, SLL (undocumented) and SRL, selected by `operation`.
+    fn rotate_shift(&mut self, operation: u8, value: u8) -> u8 {
+        let carry_in = self.regs.main.get_flag(Flag::C) as u8;
+
+        let (result, carry) = match operation {
+            0b000 => (value.rotate_left(1), value & 0x80 != 0),
+            0b001 => (value.rotate_right(1), value & 0x01 != 0),
+            0b010 => (value << 1 | carry_in, value & 0x80 != 0),
+            0b011 => (value >> 1 | carry_in << 7, value & 0x01 != 0),
+            0b100 => (value << 1, value & 0x80 != 0),
+            0b101 => (value >> 1 | (value & 0x80), value & 0x01 != 0),
+            0b110 => (value << 1 | 0x01, value & 0x80 != 0),
+            _ => (value >> 1, value & 0x01 != 0),
+        };
+
+        self.update_logic_flags(result);
+        self.regs.main.update_flag(Flag::C, carry);
+        result
+    }
+
+    /// BIT n: X/Y come from `xy_source`, which is the register itself, the
+    /// high byte of IX+d, or MEMPTR for BIT n,(HL).
+    fn bit(&mut self, bit: u8, value: u8, xy_source: u8) {
+        let is_set = value & (1 << bit) != 0;
+
+        self.regs.main.update_flag(Flag::S, bit == 7 && is_set);
+        self.regs.main.update_flag(Flag::Z, !is_set);
+        self.update_xy_flags(xy_source);
+        self.regs.main.set_flag(Flag::H);
+        self.regs.main.update_flag(Flag::PV, !is_set);
+        self.regs.main.reset_flag(Flag::N);
+    }
+
+    /// Opcodes missing from the ED table behave as two NOPs.
     fn decode_prefix_ed(&mut self, opcode: u8) -> Result<(), String> {
         match opcode {
+            op if op & 0b11000111 == 0b01000000 => self.in_r_c(op)?,
+            op if op & 0b11000111 == 0b01000001 => self.out_c_r(op)?,
+            0x42 | 0x52 | 0x62 | 0x72 => self.sbc_hl_rr(opcode),
+            0x43 | 0x53 | 0x63 | 0x73 => self.ld_nn_rr(opcode),
+            0x44 | 0x4C | 0x54 | 0x5C | 0x64 | 0x6C | 0x74 | 0x7C => self.neg(),
+            0x45 | 0x4D | 0x55 | 0x5D | 0x65 | 0x6D | 0x75 | 0x7D => self.retn(),
+            0x46 | 0x4E | 0x66 | 0x6E => self.im(0),
+            0x47 => self.ld_i_a(),
+            0x4A | 0x5A | 0x6A | 0x7A => self.adc_hl_rr(opcode),
+            0x4B | 0x5B | 0x6B | 0x7B => self.ld_rr_nn_indirect(opcode),
+            0x4F => self.ld_r_a(),
+            0x56 | 0x76 => self.im(1),
             0x57 => self.ld_a_i(),
-            _ => return Err(format!("ED prefixed opcode {:#04X} not implemented", opcode))
+            0x5E | 0x7E => self.im(2),
+            0x5F => self.ld_a_r(),
+            0x67 => self.rrd(),
+            0x6F => self.rld(),
+            0xA0 | 0xA8 | 0xB0 | 0xB8 => self.ldi_ldd(opcode),
+            0xA1 | 0xA9 | 0xB1 | 0xB9 => self.cpi_cpd(opcode),
+            0xA2 | 0xAA | 0xB2 | 0xBA => self.ini_ind(opcode),
+            0xA3 | 0xAB | 0xB3 | 0xBB => self.outi_outd(opcode),
+            _ => self.nop(),
         }
 
         self.prefix = None;
@@ -125,16 +466,13 @@ impl CUnit {
         Ok(())
     }
 
-    fn nop(&mut self) {
-        self.clock.borrow_mut().add(1);
-    }
+    fn nop(&mut self) {}
 
     fn ld_r_r(&mut self, opcode: u8) -> Result<(), String> {
         let dst = (opcode & 0b00111000) >> 3;
         let src = opcode & 0b00000111;
 
-        self.regs.main.set_reg(dst, self.regs.main.get_reg(src)?)?;
-        self.clock.borrow_mut().add(1);
+        self.set_reg(dst, self.get_reg(src)?)?;
 
         Ok(())
     }
@@ -148,7 +486,6 @@ impl CUnit {
         let address = self.get_address_by_address_mode();
         
         self.regs.main.set_reg(dst, self.bus.borrow().read(address))?;
-        self.clock.borrow_mut().add(if self.address_mode.is_some() { 4 } else { 1 });
 
         Ok(())
     }
@@ -162,7 +499,6 @@ impl CUnit {
         let address = self.get_address_by_address_mode();
         
         self.bus.borrow_mut().write(address, self.regs.main.get_reg(src)?);
-        self.clock.borrow_mut().add(if self.address_mode.is_some() { 4 } else { 1 });
 
         Ok(())
     }
@@ -174,70 +510,816 @@ impl CUnit {
             return Err(format!("Invalid opcode for ld_r_n {:#04X}", opcode));
         }
         
-        self.regs.main.set_reg(dst, self.bus.borrow().read(self.regs.pc))?;
-        self.regs.pc += 1;
+        let value = self.fetch_byte();
+        self.set_reg(dst, value)?;
 
-        self.clock.borrow_mut().add(1);
         Ok(())
     }
 
+    /// LD (IX+d),n fetches n before working out the address, in 2 T-states
+    /// instead of the 5 the other indexed forms take.
     fn ld_hl_n(&mut self) {
-        let address = self.get_address_by_address_mode();
-        let value = self.bus.borrow().read(self.regs.pc);
-        self.regs.pc += 1;
+        let address = match self.address_mode {
+            None => self.regs.main.hl(),
+            Some(_) => {
+                let d = self.fetch_byte();
+                self.get_indexed_address(d)
+            }
+        };
+        let value = self.fetch_byte();
+        if self.address_mode.is_some() {
+            self.internal(self.regs.pc.wrapping_sub(1), 2);
+        }
         self.bus.borrow_mut().write(address, value);
-        self.clock.borrow_mut().add(1);
     }
     
-    fn halt(&mut self, opcode: u8) -> Result<(), String>{
-        if opcode != 0x76 {
-            return Err(format!("Invalid opcode for halt {:#04X}", opcode));
-        }
+    fn halt(&mut self) {
         self.status = Status::Halted;
-        self.clock.borrow_mut().add(1);
-        Ok(())
     }
     
     fn ld_bc_a(&mut self) {
-        self.bus.borrow_mut().write(self.regs.main.bc(), self.regs.main.a());
-        self.clock.borrow_mut().add(1);
+        self.store_a(self.regs.main.bc());
     }
 
     fn ld_de_a(&mut self) {
-        self.bus.borrow_mut().write(self.regs.main.de(), self.regs.main.a());
-        self.clock.borrow_mut().add(1);
+        self.store_a(self.regs.main.de());
     }
 
     fn ld_nn_a(&mut self) {
-        let address = self.bus.borrow().read_word(self.regs.pc);
-        self.regs.pc += 2;
-        self.bus.borrow_mut().write(address, self.regs.main.a());
-        self.clock.borrow_mut().add(1);
+        let address = self.fetch_word();
+        self.store_a(address);
+    }
+
+    /// Writes A to memory. MEMPTR ends up with A in its high byte and the
+    /// low byte of the next address.
+    fn store_a(&mut self, address: u16) {
+        let a = self.regs.main.a();
+        self.bus.borrow_mut().write(address, a);
+        self.regs.wz = (a as u16) << 8 | (address.wrapping_add(1) & 0x00FF);
     }
 
     fn ld_a_bc(&mut self) {
-        self.regs.main.set_a(self.bus.borrow().read(self.regs.main.bc()));
-        self.clock.borrow_mut().add(1);
+        self.load_a(self.regs.main.bc());
     }
 
     fn ld_a_de(&mut self) {
-        self.regs.main.set_a(self.bus.borrow().read(self.regs.main.de()));
-        self.clock.borrow_mut().add(1);
+        self.load_a(self.regs.main.de());
     }
 
     fn ld_a_nn(&mut self) {
-        self.regs.main.set_a(self.bus.borrow().read(self.bus.borrow().read_word(self.regs.pc)));
-        self.regs.pc += 2;
-        self.clock.borrow_mut().add(1);
+        let address = self.fetch_word();
+        self.load_a(address);
+    }
+
+    fn load_a(&mut self, address: u16) {
+        self.regs.main.set_a(self.bus.borrow().read(address));
+        self.regs.wz = address.wrapping_add(1);
+    }
+
+    fn ld_rr_nn(&mut self, opcode: u8) {
+        let value = self.fetch_word();
+        self.set_rp((opcode & 0b00110000) >> 4, value);
+    }
+
+    fn ld_nn_hl(&mut self) {
+        let address = self.fetch_word();
+        self.write_word(address, self.get_index_hl());
+        self.regs.wz = address.wrapping_add(1);
+    }
+
+    fn ld_hl_nn(&mut self) {
+        let address = self.fetch_word();
+        let value = self.read_word(address);
+        self.set_index_hl(value);
+        self.regs.wz = address.wrapping_add(1);
+    }
+
+    fn ld_nn_rr(&mut self, opcode: u8) {
+        let address = self.fetch_word();
+        self.write_word(address, self.get_rp((opcode & 0b00110000) >> 4));
+        self.regs.wz = address.wrapping_add(1);
+    }
+
+    fn ld_rr_nn_indirect(&mut self, opcode: u8) {
+        let address = self.fetch_word();
+        let value = self.read_word(address);
+        self.set_rp((opcode & 0b00110000) >> 4, value);
+        self.regs.wz = address.wrapping_add(1);
+    }
+
+    /// Condition encoded in bits 3-5 of an opcode: NZ, Z, NC, C, PO, PE, P, M.
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0b000 => !self.regs.main.get_flag(Flag::Z),
+            0b001 => self.regs.main.get_flag(Flag::Z),
+            0b010 => !self.regs.main.get_flag(Flag::C),
+            0b011 => self.regs.main.get_flag(Flag::C),
+            0b100 => !self.regs.main.get_flag(Flag::PV),
+            0b101 => self.regs.main.get_flag(Flag::PV),
+            0b110 => !self.regs.main.get_flag(Flag::S),
+            _ => self.regs.main.get_flag(Flag::S),
+        }
+    }
+
+    fn jp(&mut self) {
+        self.regs.pc = self.fetch_word();
+        self.regs.wz = self.regs.pc;
+    }
+
+    /// JP cc and CALL cc load MEMPTR with the target whether or not they jump.
+    fn jp_cc(&mut self, opcode: u8) {
+        let address = self.fetch_word();
+        self.regs.wz = address;
+        if self.condition((opcode & 0b00111000) >> 3) {
+            self.regs.pc = address;
+        }
+    }
+
+    fn jp_hl(&mut self) {
+        self.regs.pc = self.get_index_hl();
+    }
+
+    /// Taken relative jumps spend 5 T-states with the offset's address on
+    /// the bus.
+    fn jump_relative(&mut self, offset: u8) {
+        self.internal(self.regs.pc.wrapping_sub(1), 5);
+        self.regs.pc = self.regs.pc.wrapping_add(offset as i8 as u16);
+        self.regs.wz = self.regs.pc;
+    }
+
+    fn jr(&mut self) {
+        let offset = self.fetch_byte();
+        self.jump_relative(offset);
+    }
+
+    fn jr_cc(&mut self, opcode: u8) {
+        let offset = self.fetch_byte();
+        if self.condition((opcode & 0b00011000) >> 3) {
+            self.jump_relative(offset);
+        }
+    }
+
+    fn djnz(&mut self) {
+        self.internal(self.ir(), 1);
+        let offset = self.fetch_byte();
+        let b = self.regs.main.b().wrapping_sub(1);
+        self.regs.main.set_b(b);
+        if b != 0 {
+            self.jump_relative(offset);
+        }
+    }
+
+    fn call(&mut self) {
+        let address = self.fetch_word();
+        self.internal(self.regs.pc.wrapping_sub(1), 1);
+        self.push_word(self.regs.pc);
+        self.regs.pc = address;
+        self.regs.wz = address;
+    }
+
+    fn call_cc(&mut self, opcode: u8) {
+        let address = self.fetch_word();
+        self.regs.wz = address;
+        if self.condition((opcode & 0b00111000) >> 3) {
+            self.internal(self.regs.pc.wrapping_sub(1), 1);
+            self.push_word(self.regs.pc);
+            self.regs.pc = address;
+        }
+    }
+
+    fn ret(&mut self) {
+        self.regs.pc = self.pop_word();
+        self.regs.wz = self.regs.pc;
+    }
+
+    fn ret_cc(&mut self, opcode: u8) {
+        self.internal(self.ir(), 1);
+        if self.condition((opcode & 0b00111000) >> 3) {
+            self.regs.pc = self.pop_word();
+            self.regs.wz = self.regs.pc;
+        }
+    }
+
+    /// RETN and RETI: both restore IFF1 from IFF2 on real silicon.
+    fn retn(&mut self) {
+        self.regs.pc = self.pop_word();
+        self.regs.wz = self.regs.pc;
+        self.regs.iff1 = self.regs.iff2;
+    }
+
+    fn ex_af_af(&mut self) {
+        self.regs.swap_af();
+    }
+
+    fn exx(&mut self) {
+        self.regs.swap_main();
+    }
+
+    /// EX DE,HL ignores DD/FD prefixes.
+    fn ex_de_hl(&mut self) {
+        let de = self.regs.main.de();
+        self.regs.main.set_de(self.regs.main.hl());
+        self.regs.main.set_hl(de);
+    }
+
+    fn ex_sp_hl(&mut self) {
+        let sp = self.regs.sp;
+        let value = self.read_word(sp);
+        self.internal(sp.wrapping_add(1), 1);
+        // written back high byte first
+        let hl = self.get_index_hl();
+        self.bus.borrow_mut().write(sp.wrapping_add(1), (hl >> 8) as u8);
+        self.bus.borrow_mut().write(sp, hl as u8);
+        self.internal(sp, 2);
+        self.set_index_hl(value);
+        self.regs.wz = value;
+    }
+
+    fn scf(&mut self) {
+        self.update_xy_flags(self.regs.main.a());
+        self.regs.main.reset_flag(Flag::H);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.set_flag(Flag::C);
+    }
+
+    fn ccf(&mut self) {
+        let carry = self.regs.main.get_flag(Flag::C);
+        self.update_xy_flags(self.regs.main.a());
+        self.regs.main.update_flag(Flag::H, carry);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, !carry);
+    }
+
+    fn cpl(&mut self) {
+        let result = !self.regs.main.a();
+        self.regs.main.set_a(result);
+        self.update_xy_flags(result);
+        self.regs.main.set_flag(Flag::H);
+        self.regs.main.set_flag(Flag::N);
+    }
+
+    fn daa(&mut self) {
+        let a = self.regs.main.a();
+        let subtract = self.regs.main.get_flag(Flag::N);
+        let mut carry = self.regs.main.get_flag(Flag::C);
+        let mut correction = 0;
+
+        if self.regs.main.get_flag(Flag::H) || a & 0x0F > 0x09 {
+            correction |= 0x06;
+        }
+        if carry || a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+
+        let (result, half) = if subtract {
+            (a.wrapping_sub(correction), self.regs.main.get_flag(Flag::H) && a & 0x0F < 0x06)
+        } else {
+            (a.wrapping_add(correction), a & 0x0F > 0x09)
+        };
+
+        self.regs.main.set_a(result);
+        self.update_logic_flags(result);
+        self.regs.main.update_flag(Flag::H, half);
+        self.regs.main.update_flag(Flag::N, subtract);
+        self.regs.main.update_flag(Flag::C, carry);
+    }
+
+    /// RLCA, RRCA, RLA and RRA: like their CB counterparts but S, Z and PV
+    /// are left alone.
+    fn rotate_a(&mut self, opcode: u8) {
+        let a = self.regs.main.a();
+        let carry_in = self.regs.main.get_flag(Flag::C) as u8;
+
+        let (result, carry) = match (opcode & 0b00011000) >> 3 {
+            0b00 => (a.rotate_left(1), a & 0x80 != 0),
+            0b01 => (a.rotate_right(1), a & 0x01 != 0),
+            0b10 => (a << 1 | carry_in, a & 0x80 != 0),
+            _ => (a >> 1 | carry_in << 7, a & 0x01 != 0),
+        };
+
+        self.regs.main.set_a(result);
+        self.update_xy_flags(result);
+        self.regs.main.reset_flag(Flag::H);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, carry);
+    }
+
+    fn di(&mut self) {
+        self.regs.iff1 = false;
+        self.regs.iff2 = false;
+    }
+
+    /// Interrupts are not accepted until the instruction after EI completes.
+    fn ei(&mut self) {
+        self.regs.iff1 = true;
+        self.regs.iff2 = true;
+        self.int_delayed = true;
+    }
+
+    fn rst(&mut self, opcode: u8) {
+        self.internal(self.ir(), 1);
+        self.push_word(self.regs.pc);
+        self.regs.pc = (opcode & 0b00111000) as u16;
+        self.regs.wz = self.regs.pc;
+    }
+
+    fn ld_sp_hl(&mut self) {
+        self.internal(self.ir(), 2);
+        self.regs.sp = self.get_index_hl();
+    }
+
+    fn push_qq(&mut self, opcode: u8) {
+        self.internal(self.ir(), 1);
+        self.push_word(self.get_rp2((opcode & 0b00110000) >> 4));
+    }
+
+    fn pop_qq(&mut self, opcode: u8) {
+        let value = self.pop_word();
+        self.set_rp2((opcode & 0b00110000) >> 4, value);
     }
     
     fn ld_a_i(&mut self) {
         self.regs.main.set_a(self.regs.i);
-        self.clock.borrow_mut().add(2);
-        if self.regs.i & 0b10000000 > 0 { self.regs.main.set_flag(Flag::S) } else { self.regs.main.reset_flag(Flag::S) }
-        if self.regs.i == 0 { self.regs.main.set_flag(Flag::Z) } else { self.regs.main.reset_flag(Flag::Z) }
+        self.internal(self.ir(), 1);
+        self.update_sz_flags(self.regs.i);
+        self.update_xy_flags(self.regs.i);
+        self.regs.main.reset_flag(Flag::H);
+        self.regs.main.update_flag(Flag::PV, self.regs.iff2);
+        self.regs.main.reset_flag(Flag::N);
+    }
+
+    fn ld_a_r(&mut self) {
+        self.regs.main.set_a(self.regs.r);
+        self.internal(self.ir(), 1);
+        self.update_sz_flags(self.regs.r);
+        self.update_xy_flags(self.regs.r);
+        self.regs.main.reset_flag(Flag::H);
+        self.regs.main.update_flag(Flag::PV, self.regs.iff2);
+        self.regs.main.reset_flag(Flag::N);
+    }
+
+    fn ld_i_a(&mut self) {
+        self.internal(self.ir(), 1);
+        self.regs.i = self.regs.main.a();
+    }
+
+    fn ld_r_a(&mut self) {
+        self.internal(self.ir(), 1);
+        self.regs.r = self.regs.main.a();
+    }
+
+    fn im(&mut self, mode: u8) {
+        self.regs.im = mode;
+    }
+
+    fn neg(&mut self) {
+        let value = self.regs.main.a();
+        self.regs.main.set_a(0);
+        self.sub_a(value, false, true);
+    }
+
+    fn update_sz_flags(&mut self, value: u8) {
+        self.regs.main.update_flag(Flag::S, value & 0b10000000 != 0);
+        self.regs.main.update_flag(Flag::Z, value == 0);
+    }
+
+    /// Undocumented flags: bits 5 and 3 of F copy the same bits of `value`.
+    fn update_xy_flags(&mut self, value: u8) {
+        self.regs.main.update_flag(Flag::Y, value & 0b00100000 != 0);
+        self.regs.main.update_flag(Flag::X, value & 0b00001000 != 0);
+    }
+
+    fn read_operand_hl(&mut self) -> u8 {
+        let address = self.get_address_by_address_mode();
+        self.bus.borrow().read(address)
+    }
+
+    fn alu_r(&mut self, opcode: u8) -> Result<(), String> {
+        let src = opcode & 0b00000111;
+
+        let value = if src == 0b110 {
+            self.read_operand_hl()
+        } else {
+            self.get_reg(src)?
+        };
+
+        self.alu_a((opcode & 0b00111000) >> 3, value);
+
+        Ok(())
+    }
+
+    fn alu_n(&mut self, opcode: u8) {
+        let value = self.fetch_byte();
+
+        self.alu_a((opcode & 0b00111000) >> 3, value);
+    }
+
+    fn alu_a(&mut self, operation: u8, value: u8) {
+        match operation {
+            0b000 => self.add_a(value, false),
+            0b001 => self.add_a(value, true),
+            0b010 => self.sub_a(value, false, true),
+            0b011 => self.sub_a(value, true, true),
+            0b100 => self.and_a(value),
+            0b101 => self.xor_a(value),
+            0b110 => self.or_a(value),
+            _ => self.sub_a(value, false, false),
+        }
+    }
+
+    fn add_a(&mut self, value: u8, with_carry: bool) {
+        let a = self.regs.main.a();
+        let carry = (with_carry && self.regs.main.get_flag(Flag::C)) as u8;
+        let sum = a as u16 + value as u16 + carry as u16;
+        let result = sum as u8;
+
+        self.regs.main.set_a(result);
+        self.update_sz_flags(result);
+        self.update_xy_flags(result);
+        self.regs.main.update_flag(Flag::H, (a & 0x0F) + (value & 0x0F) + carry > 0x0F);
+        self.regs.main.update_flag(Flag::PV, (a ^ value) & 0x80 == 0 && (a ^ result) & 0x80 != 0);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, sum > 0xFF);
+    }
+
+    /// Subtracts `value` from A, leaving A untouched when `store` is false (CP).
+    /// CP takes X/Y from the operand rather than from the discarded result.
+    fn sub_a(&mut self, value: u8, with_carry: bool, store: bool) {
+        let a = self.regs.main.a();
+        let carry = (with_carry && self.regs.main.get_flag(Flag::C)) as u8;
+        let result = a.wrapping_sub(value).wrapping_sub(carry);
+
+        if store {
+            self.regs.main.set_a(result);
+        }
+        self.update_sz_flags(result);
+        self.update_xy_flags(if store { result } else { value });
+        self.regs.main.update_flag(Flag::H, (a & 0x0F) < (value & 0x0F) + carry);
+        self.regs.main.update_flag(Flag::PV, (a ^ value) & 0x80 != 0 && (a ^ result) & 0x80 != 0);
+        self.regs.main.set_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, (a as u16) < value as u16 + carry as u16);
+    }
+
+    fn and_a(&mut self, value: u8) {
+        let result = self.regs.main.a() & value;
+        self.regs.main.set_a(result);
+        self.update_logic_flags(result);
+        self.regs.main.set_flag(Flag::H);
+    }
+
+    fn xor_a(&mut self, value: u8) {
+        let result = self.regs.main.a() ^ value;
+        self.regs.main.set_a(result);
+        self.update_logic_flags(result);
+    }
+
+    fn or_a(&mut self, value: u8) {
+        let result = self.regs.main.a() | value;
+        self.regs.main.set_a(result);
+        self.update_logic_flags(result);
+    }
+
+    fn update_logic_flags(&mut self, result: u8) {
+        self.update_sz_flags(result);
+        self.update_xy_flags(result);
+        self.regs.main.reset_flag(Flag::H);
+        self.regs.main.update_flag(Flag::PV, result.count_ones() & 1 == 0);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.reset_flag(Flag::C);
+    }
+
+    fn inc_r(&mut self, opcode: u8) -> Result<(), String> {
+        self.modify_r(opcode, Self::inc_value)
+    }
+
+    fn dec_r(&mut self, opcode: u8) -> Result<(), String> {
+        self.modify_r(opcode, Self::dec_value)
+    }
+
+    /// Read-modify-write on the register selected by bits 3-5 of `opcode`,
+    /// or on (HL)/(IX+d)/(IY+d) when it selects 0b110.
+    fn modify_r(&mut self, opcode: u8, operation: fn(&mut Self, u8) -> u8) -> Result<(), String> {
+        let dst = (opcode & 0b00111000) >> 3;
+
+        if dst == 0b110 {
+            let address = self.get_address_by_address_mode();
+            let value = self.bus.borrow().read(address);
+            self.internal(address, 1);
+            let result = operation(self, value);
+            self.bus.borrow_mut().write(address, result);
+        } else {
+            let value = self.get_reg(dst)?;
+            let result = operation(self, value);
+            self.set_reg(dst, result)?;
+        }
+
+        Ok(())
+    }
+
+    fn inc_value(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.update_sz_flags(result);
+        self.update_xy_flags(result);
+        self.regs.main.update_flag(Flag::H, value & 0x0F == 0x0F);
+        self.regs.main.update_flag(Flag::PV, value == 0x7F);
+        self.regs.main.reset_flag(Flag::N);
+        result
+    }
+
+    fn dec_value(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.update_sz_flags(result);
+        self.update_xy_flags(result);
+        self.regs.main.update_flag(Flag::H, value & 0x0F == 0x00);
+        self.regs.main.update_flag(Flag::PV, value == 0x80);
+        self.regs.main.set_flag(Flag::N);
+        result
+    }
+
+    fn inc_rr(&mut self, opcode: u8) {
+        let index = (opcode & 0b00110000) >> 4;
+        self.internal(self.ir(), 2);
+        self.set_rp(index, self.get_rp(index).wrapping_add(1));
+    }
+
+    fn dec_rr(&mut self, opcode: u8) {
+        let index = (opcode & 0b00110000) >> 4;
+        self.internal(self.ir(), 2);
+        self.set_rp(index, self.get_rp(index).wrapping_sub(1));
+    }
+
+    fn add_hl_rr(&mut self, opcode: u8) {
+        let hl = self.get_index_hl();
+        let value = self.get_rp((opcode & 0b00110000) >> 4);
+        let sum = hl as u32 + value as u32;
+        let result = sum as u16;
+
+        self.regs.wz = hl.wrapping_add(1);
+        self.set_index_hl(result);
+        self.update_xy_flags((result >> 8) as u8);
+        self.regs.main.update_flag(Flag::H, (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, sum > 0xFFFF);
+        self.internal(self.ir(), 7);
+    }
+
+    fn adc_hl_rr(&mut self, opcode: u8) {
+        let hl = self.regs.main.hl();
+        let value = self.get_rp((opcode & 0b00110000) >> 4);
+        let carry = self.regs.main.get_flag(Flag::C) as u16;
+        let sum = hl as u32 + value as u32 + carry as u32;
+        let result = sum as u16;
+
+        self.regs.wz = hl.wrapping_add(1);
+        self.regs.main.set_hl(result);
+        self.update_word_flags(result);
+        self.regs.main.update_flag(Flag::H, (hl & 0x0FFF) + (value & 0x0FFF) + carry > 0x0FFF);
+        self.regs.main.update_flag(Flag::PV, (hl ^ value) & 0x8000 == 0 && (hl ^ result) & 0x8000 != 0);
+        self.regs.main.reset_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, sum > 0xFFFF);
+        self.internal(self.ir(), 7);
+    }
+
+    fn sbc_hl_rr(&mut self, opcode: u8) {
+        let hl = self.regs.main.hl();
+        let value = self.get_rp((opcode & 0b00110000) >> 4);
+        let carry = self.regs.main.get_flag(Flag::C) as u16;
+        let result = hl.wrapping_sub(value).wrapping_sub(carry);
+
+        self.regs.wz = hl.wrapping_add(1);
+        self.regs.main.set_hl(result);
+        self.update_word_flags(result);
+        self.regs.main.update_flag(Flag::H, (hl & 0x0FFF) < (value & 0x0FFF) + carry);
+        self.regs.main.update_flag(Flag::PV, (hl ^ value) & 0x8000 != 0 && (hl ^ result) & 0x8000 != 0);
+        self.regs.main.set_flag(Flag::N);
+        self.regs.main.update_flag(Flag::C, (hl as u32) < value as u32 + carry as u32);
+        self.internal(self.ir(), 7);
+    }
+
+    /// S, Z and X/Y for 16-bit results, taken from the high byte.
+    fn update_word_flags(&mut self, result: u16) {
+        self.regs.main.update_flag(Flag::S, result & 0x8000 != 0);
+        self.regs.main.update_flag(Flag::Z, result == 0);
+        self.update_xy_flags((result >> 8) as u8);
+    }
+
+    fn rrd(&mut self) {
+        let address = self.regs.main.hl();
+        let value = self.bus.borrow().read(address);
+        let a = self.regs.main.a();
+        self.internal(address, 4);
+
+        self.bus.borrow_mut().write(address, (a << 4) | (value >> 4));
+        self.regs.main.set_a((a & 0xF0) | (value & 0x0F));
+        self.regs.wz = address.wrapping_add(1);
+        self.update_rotate_digit_flags();
+    }
+
+    fn rld(&mut self) {
+        let address = self.regs.main.hl();
+        let value = self.bus.borrow().read(address);
+        let a = self.regs.main.a();
+        self.internal(address, 4);
+
+        self.bus.borrow_mut().write(address, (value << 4) | (a & 0x0F));
+        self.regs.main.set_a((a & 0xF0) | (value >> 4));
+        self.regs.wz = address.wrapping_add(1);
+        self.update_rotate_digit_flags();
+    }
+
+    fn update_rotate_digit_flags(&mut self) {
+        let carry = self.regs.main.get_flag(Flag::C);
+        self.update_logic_flags(self.regs.main.a());
+        self.regs.main.update_flag(Flag::C, carry);
+    }
+
+    /// Step applied to HL (and DE) by block instructions: bit 3 of the
+    /// opcode selects the decrementing variant.
+    fn block_step(opcode: u8) -> u16 {
+        if opcode & 0b00001000 == 0 { 1 } else { 0xFFFF }
+    }
+
+    /// Repeating block instructions rewind PC so the instruction is fetched
+    /// again, which lets interrupts be taken between iterations. The extra
+    /// 5 T-states keep `address` on the bus.
+    fn repeat_block(&mut self, address: u16) {
+        self.internal(address, 5);
+        self.regs.pc = self.regs.pc.wrapping_sub(2);
+    }
+
+    fn ldi_ldd(&mut self, opcode: u8) {
+        let step = Self::block_step(opcode);
+        let hl = self.regs.main.hl();
+        let de = self.regs.main.de();
+
+        let value = self.bus.borrow().read(hl);
+        self.bus.borrow_mut().write(de, value);
+        self.internal(de, 2);
+
+        self.regs.main.set_hl(hl.wrapping_add(step));
+        self.regs.main.set_de(de.wrapping_add(step));
+        let bc = self.regs.main.bc().wrapping_sub(1);
+        self.regs.main.set_bc(bc);
+
+        let n = value.wrapping_add(self.regs.main.a());
+        self.regs.main.update_flag(Flag::Y, n & 0b00000010 != 0);
+        self.regs.main.update_flag(Flag::X, n & 0b00001000 != 0);
         self.regs.main.reset_flag(Flag::H);
-        if self.regs.iff2 { self.regs.main.set_flag(Flag::PV) } else { self.regs.main.reset_flag(Flag::PV) }
+        self.regs.main.update_flag(Flag::PV, bc != 0);
         self.regs.main.reset_flag(Flag::N);
+
+        if opcode & 0b00010000 != 0 && bc != 0 {
+            self.repeat_block(de);
+            self.regs.wz = self.regs.pc.wrapping_add(1);
+        }
+    }
+
+    fn cpi_cpd(&mut self, opcode: u8) {
+        let step = Self::block_step(opcode);
+        let hl = self.regs.main.hl();
+        let a = self.regs.main.a();
+
+        let value = self.bus.borrow().read(hl);
+        self.internal(hl, 5);
+
+        let result = a.wrapping_sub(value);
+        let half = (a & 0x0F) < (value & 0x0F);
+        self.regs.main.set_hl(hl.wrapping_add(step));
+        self.regs.wz = self.regs.wz.wrapping_add(step);
+        let bc = self.regs.main.bc().wrapping_sub(1);
+        self.regs.main.set_bc(bc);
+
+        let n = result.wrapping_sub(half as u8);
+        self.update_sz_flags(result);
+        self.regs.main.update_flag(Flag::Y, n & 0b00000010 != 0);
+        self.regs.main.update_flag(Flag::X, n & 0b00001000 != 0);
+        self.regs.main.update_flag(Flag::H, half);
+        self.regs.main.update_flag(Flag::PV, bc != 0);
+        self.regs.main.set_flag(Flag::N);
+
+        if opcode & 0b00010000 != 0 && bc != 0 && result != 0 {
+            self.repeat_block(hl);
+            self.regs.wz = self.regs.pc.wrapping_add(1);
+        }
+    }
+
+    fn ini_ind(&mut self, opcode: u8) {
+        let step = Self::block_step(opcode);
+        self.internal(self.ir(), 1);
+
+        let bc = self.regs.main.bc();
+        let value = self.read_port(bc);
+        let hl = self.regs.main.hl();
+        self.bus.borrow_mut().write(hl, value);
+
+        self.regs.wz = bc.wrapping_add(step);
+        self.regs.main.set_hl(hl.wrapping_add(step));
+        let b = self.regs.main.b().wrapping_sub(1);
+        self.regs.main.set_b(b);
+
+        let k = value as u16 + self.regs.main.c().wrapping_add(step as u8) as u16;
+        self.update_block_io_flags(value, k);
+
+        if opcode & 0b00010000 != 0 && b != 0 {
+            self.repeat_block(hl);
+        }
+    }
+
+    fn outi_outd(&mut self, opcode: u8) {
+        let step = Self::block_step(opcode);
+        self.internal(self.ir(), 1);
+
+        let hl = self.regs.main.hl();
+        let value = self.bus.borrow().read(hl);
+        let b = self.regs.main.b().wrapping_sub(1);
+        self.regs.main.set_b(b);
+        let bc = self.regs.main.bc();
+        self.write_port(bc, value);
+
+        self.regs.wz = bc.wrapping_add(step);
+        self.regs.main.set_hl(hl.wrapping_add(step));
+
+        let k = value as u16 + self.regs.main.l() as u16;
+        self.update_block_io_flags(value, k);
+
+        if opcode & 0b00010000 != 0 && b != 0 {
+            self.repeat_block(bc);
+        }
+    }
+
+    /// Flags after INI/IND/OUTI/OUTD, as documented in "The Undocumented Z80
+    /// Documented": `k` is the transferred byte plus C±1 (input) or L (output).
+    fn update_block_io_flags(&mut self, value: u8, k: u16) {
+        let b = self.regs.main.b();
+
+        self.update_sz_flags(b);
+        self.update_xy_flags(b);
+        self.regs.main.update_flag(Flag::H, k > 0xFF);
+        self.regs.main.update_flag(Flag::PV, ((k as u8 & 0x07) ^ b).count_ones() & 1 == 0);
+        self.regs.main.update_flag(Flag::N, value & 0x80 != 0);
+        self.regs.main.update_flag(Flag::C, k > 0xFF);
+    }
+
+    /// I/O cycles take 4 T-states, more if the ULA contends them.
+    fn io_cycle(&mut self, port: u16) {
+        let bus = self.bus.borrow();
+        match bus.contention() {
+            Some(contention) => contention.io_cycle(port, bus.is_contended(port)),
+            None => self.clock.borrow_mut().add(4),
+        }
+    }
+
+    fn read_port(&mut self, port: u16) -> u8 {
+        self.io_cycle(port);
+        self.bus.borrow_mut().read_port(port)
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        self.io_cycle(port);
+        self.bus.borrow_mut().write_port(port, value);
+    }
+
+    fn in_a_n(&mut self) {
+        let port = (self.regs.main.a() as u16) << 8 | self.fetch_byte() as u16;
+        let value = self.read_port(port);
+        self.regs.main.set_a(value);
+        self.regs.wz = port.wrapping_add(1);
+    }
+
+    fn out_n_a(&mut self) {
+        let a = self.regs.main.a();
+        let n = self.fetch_byte();
+        self.write_port((a as u16) << 8 | n as u16, a);
+        self.regs.wz = (a as u16) << 8 | n.wrapping_add(1) as u16;
+    }
+
+    /// IN r,(C). The 0b110 encoding (IN F,(C)) only updates the flags.
+    fn in_r_c(&mut self, opcode: u8) -> Result<(), String> {
+        let dst = (opcode & 0b00111000) >> 3;
+        let bc = self.regs.main.bc();
+        let value = self.read_port(bc);
+
+        if dst != 0b110 {
+            self.regs.main.set_reg(dst, value)?;
+        }
+
+        let carry = self.regs.main.get_flag(Flag::C);
+        self.update_logic_flags(value);
+        self.regs.main.update_flag(Flag::C, carry);
+        self.regs.wz = bc.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// OUT (C),r. The 0b110 encoding outputs 0 on NMOS parts.
+    fn out_c_r(&mut self, opcode: u8) -> Result<(), String> {
+        let src = (opcode & 0b00111000) >> 3;
+        let value = if src == 0b110 { 0 } else { self.regs.main.get_reg(src)? };
+        let bc = self.regs.main.bc();
+        self.write_port(bc, value);
+        self.regs.wz = bc.wrapping_add(1);
+
+        Ok(())
     }
 }
\ No newline at end of file
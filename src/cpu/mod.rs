@@ -1,19 +1,51 @@
-mod regs;
+pub mod regs;
 mod cu;
 
 use std::{cell::RefCell, rc::Rc};
 
-use crate::{bus::Bus, clock::Clock};
+use crate::{bus::Bus, clock::Clock, contention::Contention, model::Model, tape::tap::Tap};
 
-use self::{cu::{CUnit, Status}, regs::Registers};
+use self::{cu::{CUnit, Status}, regs::{Flag, Registers}};
 
 pub type RefBus = Rc<RefCell<Bus>>;
 pub type RefClock = Rc<RefCell<Clock>>;
 
+/// Entry point of the ROM LD-BYTES routine.
+const LD_BYTES: u16 = 0x0556;
+/// Its first instructions, INC D; EX AF,AF'; DEC D, which tell the 48K ROM
+/// (or the 128K ROM 1) apart from other ROMs paged in at the time.
+const LD_BYTES_CODE: [u8; 3] = [0x14, 0x08, 0x15];
+
+/// Why a run loop returned.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    /// The T-state budget was consumed.
+    Budget,
+    /// An instruction failed to execute.
+    Error(String),
+}
+
+/// Outcome of `Cpu::run_for` and `Cpu::run_frame`.
+#[derive(Debug, PartialEq)]
+pub struct RunSummary {
+    /// Instructions executed, interrupt acknowledges included.
+    pub instructions: u32,
+    /// T-states elapsed.
+    pub t_states: u32,
+    /// T-states run past the budget by the last instruction.
+    pub overshoot: u32,
+    pub reason: StopReason,
+}
+
 pub struct Cpu {
     bus: RefBus,
     clock: RefClock,
     cu: CUnit,
+    model: Model,
+    int_line: Option<u8>,
+    nmi_pending: bool,
+    /// Tape fast-loaded through the LD-BYTES trap, and its next block.
+    tape: Option<(Tap, usize)>,
 }
 
 impl Cpu {
@@ -22,6 +54,10 @@ impl Cpu {
             bus: bus.clone(),
             clock: clock.clone(),
             cu: CUnit::new(Registers::new(), bus.clone(), clock.clone()),
+            model: Model::Spectrum48K,
+            int_line: None,
+            nmi_pending: false,
+            tape: None,
         }
     }
 
@@ -29,19 +65,94 @@ impl Cpu {
         self.cu.regs.iff1 = false;
         self.cu.regs.iff2 = false;
         self.cu.regs.pc = 0;
+        self.cu.regs.i = 0;
+        self.cu.regs.r = 0;
+        self.cu.regs.im = 0;
         self.cu.prefix = None;
+        self.cu.address_mode = None;
+        self.cu.int_delayed = false;
         self.cu.status = Status::Running;
+        self.int_line = None;
+        self.nmi_pending = false;
         self.clock.borrow_mut().reset();
     }
     
+    /// Timings used by the run loops. Defaults to the 48K. The clock frame
+    /// length and, if enabled, the bus contention follow the model, so the
+    /// three always agree.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.clock.borrow_mut().set_frame_length(model.t_states_per_frame());
+        let mut bus = self.bus.borrow_mut();
+        if bus.contention().is_some() {
+            bus.set_contention(Contention::new(model, self.clock.clone()));
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.cu.regs
+    }
+
+    /// Asserts the INT line with `data` on the data bus. The line is level
+    /// triggered: it stays active until `release_int` is called.
+    pub fn raise_int(&mut self, data: u8) {
+        self.int_line = Some(data);
+    }
+
+    pub fn release_int(&mut self) {
+        self.int_line = None;
+    }
+
+    /// Signals a falling edge on the NMI line; it is taken before the next
+    /// instruction.
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Inserts a tape for fast loading: when the ROM calls LD-BYTES the next
+    /// block is copied to memory at once.
+    pub fn insert_tape(&mut self, tap: Tap) {
+        self.tape = Some((tap, 0));
+    }
+
+    pub fn eject_tape(&mut self) {
+        self.tape = None;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        matches!(self.cu.status, Status::Halted)
+    }
+
+    /// Runs one instruction, or acknowledges a pending interrupt instead.
     pub fn execute(&mut self) -> Result<(), String> {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.cu.int_delayed = false;
+            self.cu.nmi();
+            return Ok(());
+        }
+
+        if self.cu.int_delayed {
+            self.cu.int_delayed = false;
+        } else if let (Some(data), true) = (self.int_line, self.cu.regs.iff1) {
+            return self.cu.interrupt(data);
+        }
+
+        if self.cu.regs.pc == LD_BYTES && self.cu.prefix.is_none() && self.is_ld_bytes() && self.fast_load() {
+            return Ok(());
+        }
+
         loop {
+            // The read takes the first 3 T-states of the M1 cycle and the
+            // refresh the 4th, before the opcode is decoded.
             let mut opcode = self.fetch_op();
+            self.clock.borrow_mut().add(1);
+            self.cu.regs.increment_r();
             
             if let Status::Halted = self.cu.status {
                 opcode = 0x00;
             } else {
-                self.cu.regs.pc += 1;
+                self.cu.regs.pc = self.cu.regs.pc.wrapping_add(1);
             }
     
             self.cu.decode(opcode)?;
@@ -54,6 +165,128 @@ impl Cpu {
         Ok(())
     }
 
+    /// Runs instructions until `t_states` have elapsed. The ULA frame
+    /// interrupt is driven from the clock: INT is held active (0xFF on the
+    /// data bus) during the first T-states of every frame. Stops at once
+    /// with an error if the clock or the contention was set up for another
+    /// model.
+    pub fn run_for(&mut self, t_states: u32) -> RunSummary {
+        if let Err(error) = self.check_model() {
+            return RunSummary { instructions: 0, t_states: 0, overshoot: 0, reason: StopReason::Error(error) };
+        }
+
+        let start = self.clock.borrow().read();
+        let target = start + t_states as u64;
+        let mut instructions = 0;
+        let mut reason = StopReason::Budget;
+
+        loop {
+            let (now, position) = {
+                let clock = self.clock.borrow();
+                (clock.read(), clock.frame_tics())
+            };
+            if now >= target {
+                break;
+            }
+
+            if position < self.model.interrupt_length() {
+                self.raise_int(0xFF);
+            } else {
+                self.release_int();
+            }
+
+            if let Err(error) = self.execute() {
+                reason = StopReason::Error(error);
+                break;
+            }
+            instructions += 1;
+        }
+
+        let end = self.clock.borrow().read();
+        RunSummary {
+            instructions,
+            t_states: (end - start) as u32,
+            overshoot: end.saturating_sub(target) as u32,
+            reason,
+        }
+    }
+
+    /// Runs up to the end of the current frame, so that successive calls stay
+    /// aligned to frame boundaries despite overshoots.
+    pub fn run_frame(&mut self) -> RunSummary {
+        let remaining = {
+            let clock = self.clock.borrow();
+            clock.frame_length() - clock.frame_tics()
+        };
+        self.run_for(remaining)
+    }
+
+    /// A clock framed or a contention table built for another model would
+    /// silently skew every frame: see `set_model`.
+    fn check_model(&self) -> Result<(), String> {
+        let frame_length = self.clock.borrow().frame_length();
+        if frame_length != self.model.t_states_per_frame() {
+            return Err(format!("Clock frame length {} does not match the {:?}", frame_length, self.model));
+        }
+        match self.bus.borrow().contention() {
+            Some(contention) if contention.model() != self.model => {
+                Err(format!("Contention does not match the {:?}", self.model))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_ld_bytes(&self) -> bool {
+        let bus = self.bus.borrow();
+        (0..3).all(|i| bus.peek(LD_BYTES + i) == LD_BYTES_CODE[i as usize])
+    }
+
+    /// Runs LD-BYTES on the next tape block: A holds the expected flag,
+    /// carry tells LOAD from VERIFY, IX and DE give where and how much. Like
+    /// the ROM it returns carry set on success, with IX and DE past the
+    /// bytes read. Does nothing once the tape is over.
+    fn fast_load(&mut self) -> bool {
+        let block = match &mut self.tape {
+            Some((tap, next)) if *next < tap.blocks().len() => {
+                *next += 1;
+                tap.blocks()[*next - 1].bytes().to_vec()
+            }
+            _ => return false,
+        };
+
+        let regs = &mut self.cu.regs;
+        let load = regs.main.get_flag(Flag::C);
+        let length = regs.main.de() as usize;
+        let mut success = block[0] == regs.main.a();
+
+        if success {
+            let count = length.min(block.len() - 1);
+            let mut bus = self.bus.borrow_mut();
+            for (i, byte) in block[1..=count].iter().enumerate() {
+                let address = regs.ix.wrapping_add(i as u16);
+                if load {
+                    bus.store(address, *byte);
+                } else if bus.peek(address) != *byte {
+                    success = false;
+                }
+            }
+            regs.ix = regs.ix.wrapping_add(count as u16);
+            regs.main.set_de((length - count) as u16);
+
+            // The parity byte follows the data: the XOR of everything up to
+            // it must come out 0.
+            let parity = block.get(..length + 2).map(|bytes| bytes.iter().fold(0, |parity, byte| parity ^ byte));
+            success &= parity == Some(0);
+        }
+
+        regs.main.update_flag(Flag::C, success);
+        let sp = regs.sp;
+        let bus = self.bus.borrow();
+        regs.pc = (bus.peek(sp.wrapping_add(1)) as u16) << 8 | bus.peek(sp) as u16;
+        regs.sp = sp.wrapping_add(2);
+        true
+    }
+
     fn fetch_op(&self) -> u8 {
         self.bus.borrow().read(self.cu.regs.pc)
     }
@@ -63,9 +296,37 @@ impl Cpu {
 mod test_cpu {
     use std::{cell::RefCell, rc::Rc};
 
-    use crate::{bus::Bus, clock::Clock, device::ram::Ram};
+    use crate::{bus::{Bus, IoDevice}, clock::Clock, contention::Contention, device::{ram::Ram, rom::Rom}, model::Model};
+
+    use super::{Cpu, Flag, RefBus, RefClock};
 
-    use super::{Cpu, RefBus, RefClock};
+    struct TestPort {
+        value: u8,
+        written: Rc<RefCell<Vec<(u16, u8)>>>,
+    }
+    impl IoDevice for TestPort {
+        fn get_port_mask(&self) -> u16 {
+            0x0000
+        }
+
+        fn get_port_match(&self) -> u16 {
+            0x0000
+        }
+
+        fn read_port(&mut self, port: u16) -> u8 {
+            self.value ^ (port >> 8) as u8
+        }
+
+        fn write_port(&mut self, port: u16, value: u8) {
+            self.written.borrow_mut().push((port, value));
+        }
+    }
+
+    fn add_test_port(cpu: &Cpu, value: u8) -> Rc<RefCell<Vec<(u16, u8)>>> {
+        let written = Rc::new(RefCell::new(vec![]));
+        cpu.bus.borrow_mut().add_io_device(Box::new(TestPort { value, written: Rc::clone(&written) }));
+        written
+    }
 
     fn init() -> Cpu {
         let bus: RefBus = Rc::new(RefCell::new(Bus::new()));
@@ -436,7 +697,7 @@ mod test_cpu {
         assert_eq!(cpu.cu.regs.main.a(), 0x55);
         assert_eq!(cpu.clock.borrow().read(), 9);
         assert_eq!(cpu.cu.regs.pc, 0x0002);
-        assert_eq!(cpu.cu.regs.main.f(), 0b00101001);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000001);
         
         cpu.cu.regs.i = 0x00;
         let res = cpu.execute();
@@ -444,7 +705,7 @@ mod test_cpu {
         assert_eq!(cpu.cu.regs.main.a(), 0x00);
         assert_eq!(cpu.clock.borrow().read(), 18);
         assert_eq!(cpu.cu.regs.pc, 0x0004);
-        assert_eq!(cpu.cu.regs.main.f(), 0b01101001);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000001);
 
         cpu.cu.regs.i = 0xFE;
         let res = cpu.execute();
@@ -454,4 +715,1632 @@ mod test_cpu {
         assert_eq!(cpu.cu.regs.pc, 0x0006);
         assert_eq!(cpu.cu.regs.main.f(), 0b10101001);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_add_a_r() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x80, 0x81, 0x82]);
+        cpu.cu.regs.main.set_a(0x44);
+        cpu.cu.regs.main.set_bc(0x112B);
+        cpu.cu.regs.main.set_d(0x80);
+
+        let res = cpu.execute(); // add a,b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x55);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000000);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        let res = cpu.execute(); // add a,c
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x80);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10010100);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+
+        let res = cpu.execute(); // add a,d
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x00);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000101);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+    }
+
+    #[test]
+    fn test_add_a_n_hl_ixd() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xC6, 0x01, 0x86, 0xDD, 0x86, 0x01]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x02, 0x10]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.ix = 0x0100;
+
+        let res = cpu.execute(); // add a,n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x01);
+        assert_eq!(cpu.clock.borrow().read(), 7);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // add a,(hl)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x03);
+        assert_eq!(cpu.clock.borrow().read(), 7);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // add a,(ix+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x13);
+        assert_eq!(cpu.clock.borrow().read(), 19);
+        assert_eq!(cpu.cu.regs.pc, 0x0006);
+    }
+
+    #[test]
+    fn test_adc_a_r() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x88, 0x88]);
+        cpu.cu.regs.main.set_a(0x10);
+        cpu.cu.regs.main.set_b(0x01);
+        cpu.cu.regs.main.set_f(0b00000001);
+
+        let res = cpu.execute(); // adc a,b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x12);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000000);
+
+        let res = cpu.execute(); // adc a,b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x13);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_sub_sbc() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x90, 0xD6, 0x90, 0x98, 0xDE, 0x03]);
+        cpu.cu.regs.main.set_a(0x53);
+        cpu.cu.regs.main.set_b(0x11);
+
+        let res = cpu.execute(); // sub b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x42);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000010);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        cpu.cu.regs.main.set_a(0x10);
+        let res = cpu.execute(); // sub n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x80);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10000111);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+
+        cpu.cu.regs.main.set_a(0x44);
+        cpu.cu.regs.main.set_b(0x03);
+        cpu.cu.regs.main.set_f(0b00000001);
+        let res = cpu.execute(); // sbc a,b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x40);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000010);
+
+        let res = cpu.execute(); // sbc a,n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x3D);
+        assert_eq!(cpu.clock.borrow().read(), 22);
+    }
+
+    #[test]
+    fn test_cp() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xFE, 0x44, 0xB8]);
+        cpu.cu.regs.main.set_a(0x44);
+        cpu.cu.regs.main.set_b(0x01);
+
+        let res = cpu.execute(); // cp n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x44);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000010);
+        assert_eq!(cpu.clock.borrow().read(), 7);
+
+        cpu.cu.regs.main.set_a(0x40);
+        let res = cpu.execute(); // cp b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x40);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010010);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+    }
+
+    #[test]
+    fn test_logic() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xA0, 0xF6, 0x01, 0xAF]);
+        cpu.cu.regs.main.set_a(0xC3);
+        cpu.cu.regs.main.set_b(0x81);
+        cpu.cu.regs.main.set_f(0b00000001);
+
+        let res = cpu.execute(); // and b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x81);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10010100);
+
+        cpu.cu.regs.main.set_a(0x40);
+        let res = cpu.execute(); // or n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x41);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000100);
+
+        let res = cpu.execute(); // xor a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x00);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000100);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+    }
+
+    #[test]
+    fn test_inc_dec_r() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x04, 0x0D]);
+        cpu.cu.regs.main.set_bc(0x7F01);
+        cpu.cu.regs.main.set_f(0b00000001);
+
+        let res = cpu.execute(); // inc b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0x80);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10010101);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        let res = cpu.execute(); // dec c
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.c(), 0x00);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000011);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_inc_dec_hl_ixd() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x34, 0xDD, 0x34, 0x01, 0xFD, 0x35, 0xFF]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x41, 0x10]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.ix = 0x0100;
+        cpu.cu.regs.iy = 0x0102;
+
+        let res = cpu.execute(); // inc (hl)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x42);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // inc (ix+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x11);
+        assert_eq!(cpu.clock.borrow().read(), 23);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // dec (iy+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x10);
+        assert_eq!(cpu.clock.borrow().read(), 23);
+        assert_eq!(cpu.cu.regs.pc, 0x0007);
+    }
+
+    #[test]
+    fn test_undocumented_xy_flags() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xC6, 0x28, 0xFE, 0x28, 0xFE, 0x01]);
+
+        let res = cpu.execute(); // add a,n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x28);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00101000);
+
+        cpu.cu.regs.main.set_a(0x00);
+        let res = cpu.execute(); // cp n, X/Y copied from the operand
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10111011);
+
+        cpu.cu.regs.main.set_a(0x29);
+        let res = cpu.execute(); // cp n, result 0x28 is discarded
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000010);
+    }
+
+    #[test]
+    fn test_ld_rr_nn() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x01, 0x34, 0x12, 0x31, 0xFE, 0x0F, 0xDD, 0x21, 0x78, 0x56]);
+
+        let res = cpu.execute(); // ld bc,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x1234);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld sp,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x0FFE);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld ix,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.ix, 0x5678);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0000);
+        assert_eq!(cpu.clock.borrow().read(), 14);
+        assert_eq!(cpu.cu.regs.pc, 0x000A);
+    }
+
+    #[test]
+    fn test_ld_nn_hl_and_back() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x22, 0x00, 0x01, 0xFD, 0x2A, 0x00, 0x01, 0xDD, 0x22, 0x02, 0x01]);
+        cpu.cu.regs.main.set_hl(0xBEEF);
+        cpu.cu.regs.ix = 0xCAFE;
+
+        let res = cpu.execute(); // ld (nn),hl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0xEF);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0xBE);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld iy,(nn)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.iy, 0xBEEF);
+        assert_eq!(cpu.clock.borrow().read(), 20);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld (nn),ix
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0102), 0xFE);
+        assert_eq!(cpu.bus.borrow().peek(0x0103), 0xCA);
+        assert_eq!(cpu.clock.borrow().read(), 20);
+    }
+
+    #[test]
+    fn test_ld_rr_nn_ed() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x43, 0x00, 0x01, 0xED, 0x7B, 0x00, 0x01]);
+        cpu.cu.regs.main.set_bc(0x1234);
+
+        let res = cpu.execute(); // ld (nn),bc
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x34);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x12);
+        assert_eq!(cpu.clock.borrow().read(), 20);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld sp,(nn)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x1234);
+        assert_eq!(cpu.clock.borrow().read(), 20);
+        assert_eq!(cpu.cu.regs.pc, 0x0008);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xC5, 0xF5, 0xD1, 0xDD, 0xE1, 0xDD, 0xE5, 0xF1]);
+        cpu.cu.regs.sp = 0x0200;
+        cpu.cu.regs.main.set_bc(0x1234);
+        cpu.cu.regs.main.set_af(0x5678);
+
+        let res = cpu.execute(); // push bc
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x01FE);
+        assert_eq!(cpu.bus.borrow().peek(0x01FF), 0x12);
+        assert_eq!(cpu.bus.borrow().peek(0x01FE), 0x34);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // push af
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x01FC);
+
+        let res = cpu.execute(); // pop de
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.de(), 0x5678);
+        assert_eq!(cpu.cu.regs.sp, 0x01FE);
+        assert_eq!(cpu.clock.borrow().read(), 21);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // pop ix
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.ix, 0x1234);
+        assert_eq!(cpu.cu.regs.sp, 0x0200);
+        assert_eq!(cpu.clock.borrow().read(), 14);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // push ix
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+
+        let res = cpu.execute(); // pop af
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.af(), 0x1234);
+    }
+
+    #[test]
+    fn test_ld_sp_hl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xF9, 0xFD, 0xF9]);
+        cpu.cu.regs.main.set_hl(0x1234);
+        cpu.cu.regs.iy = 0x5678;
+
+        let res = cpu.execute(); // ld sp,hl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x1234);
+        assert_eq!(cpu.clock.borrow().read(), 6);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld sp,iy
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0x5678);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+    }
+
+    #[test]
+    fn test_jp() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xC3, 0x00, 0x01]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0xCA, 0x00, 0x02, 0xF2, 0x00, 0x03]);
+        cpu.bus.borrow_mut().write_vec(0x0300, vec![0xDD, 0xE9]);
+        cpu.cu.regs.ix = 0x0400;
+
+        let res = cpu.execute(); // jp nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0100);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // jp z,nn not taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0103);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // jp p,nn taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0300);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // jp (ix)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0400);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_jr() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x18, 0x02, 0x00, 0x00, 0x38, 0xFA, 0x30, 0xFA]);
+
+        let res = cpu.execute(); // jr e
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0004);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // jr c,e not taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0006);
+        assert_eq!(cpu.clock.borrow().read(), 7);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // jr nc,e taken backwards
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+    }
+
+    #[test]
+    fn test_djnz() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x10, 0xFE]);
+        cpu.cu.regs.main.set_b(0x02);
+
+        let res = cpu.execute(); // djnz e taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0x01);
+        assert_eq!(cpu.cu.regs.pc, 0x0000);
+        assert_eq!(cpu.clock.borrow().read(), 13);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // djnz e not taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0x00);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xCD, 0x00, 0x01, 0xDC, 0x00, 0x02, 0xC4, 0x00, 0x02]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0xC9]);
+        cpu.bus.borrow_mut().write_vec(0x0200, vec![0xC8, 0xC0]);
+        cpu.cu.regs.sp = 0x0F00;
+
+        let res = cpu.execute(); // call nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0100);
+        assert_eq!(cpu.cu.regs.sp, 0x0EFE);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0x03);
+        assert_eq!(cpu.clock.borrow().read(), 17);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ret
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0003);
+        assert_eq!(cpu.cu.regs.sp, 0x0F00);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // call c,nn not taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0006);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // call nz,nn taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0200);
+        assert_eq!(cpu.clock.borrow().read(), 17);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ret z not taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0201);
+        assert_eq!(cpu.clock.borrow().read(), 5);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ret nz taken
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0009);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xE2, 0x00, 0x01]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0xEA, 0x00, 0x02]);
+        cpu.bus.borrow_mut().write_vec(0x0200, vec![0xFA, 0x00, 0x03]);
+        cpu.cu.regs.main.set_f(0b10000000);
+
+        let res = cpu.execute(); // jp po,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0100);
+
+        cpu.cu.regs.main.set_f(0b10000100);
+        let res = cpu.execute(); // jp pe,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0200);
+
+        let res = cpu.execute(); // jp m,nn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0300);
+    }
+
+    #[test]
+    fn test_rst() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0xFF]);
+        cpu.cu.regs.pc = 0x0100;
+        cpu.cu.regs.sp = 0x0F00;
+
+        let res = cpu.execute(); // rst 38h
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0038);
+        assert_eq!(cpu.cu.regs.sp, 0x0EFE);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0x01);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFF), 0x01);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+    }
+
+    #[test]
+    fn test_retn_reti() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x45]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0xED, 0x4D]);
+        cpu.bus.borrow_mut().write_vec(0x0EFC, vec![0x00, 0x01, 0x34, 0x12]);
+        cpu.cu.regs.sp = 0x0EFC;
+        cpu.cu.regs.iff2 = true;
+
+        let res = cpu.execute(); // retn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0100);
+        assert!(cpu.cu.regs.iff1);
+        assert_eq!(cpu.clock.borrow().read(), 14);
+        cpu.clock.borrow_mut().reset();
+
+        cpu.cu.regs.iff2 = false;
+        let res = cpu.execute(); // reti
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x1234);
+        assert!(!cpu.cu.regs.iff1);
+        assert_eq!(cpu.clock.borrow().read(), 14);
+    }
+
+    #[test]
+    fn test_cb_rotate_shift_r() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xCB, 0x00, 0xCB, 0x19, 0xCB, 0x32, 0xCB, 0x2B]);
+        cpu.cu.regs.main.set_bc(0x8101);
+        cpu.cu.regs.main.set_de(0x8084);
+
+        let res = cpu.execute(); // rlc b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0x03);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000101);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+
+        cpu.cu.regs.main.set_f(0b00000000);
+        let res = cpu.execute(); // rr c
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.c(), 0x00);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000101);
+
+        let res = cpu.execute(); // sll d
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.d(), 0x01);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000001);
+
+        let res = cpu.execute(); // sra e
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.e(), 0xC2);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10000000);
+        assert_eq!(cpu.clock.borrow().read(), 32);
+        assert_eq!(cpu.cu.regs.pc, 0x0008);
+    }
+
+    #[test]
+    fn test_cb_hl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xCB, 0x3E, 0xCB, 0x7F, 0xCB, 0x46, 0xCB, 0xFE, 0xCB, 0xA8]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x41]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_a(0x80);
+        cpu.cu.regs.main.set_b(0xFF);
+
+        let res = cpu.execute(); // srl (hl)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x20);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00100001);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // bit 7,a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10010001);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+        cpu.clock.borrow_mut().reset();
+
+        cpu.cu.regs.wz = 0x2800;
+        let res = cpu.execute(); // bit 0,(hl), X/Y from MEMPTR
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01111101);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // set 7,(hl)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0xA0);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // res 5,b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0xDF);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_ddcb() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xDD, 0xCB, 0x01, 0x06, 0xDD, 0xCB, 0x01, 0x10, 0xFD, 0xCB, 0xFF, 0x46, 0xFD, 0xCB, 0xFF, 0x87]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x00, 0x80]);
+        cpu.cu.regs.ix = 0x0100;
+        cpu.cu.regs.iy = 0x0102;
+
+        let res = cpu.execute(); // rlc (ix+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x01);
+        assert!(cpu.cu.regs.main.get_flag(crate::cpu::regs::Flag::C));
+        assert_eq!(cpu.clock.borrow().read(), 23);
+        assert_eq!(cpu.cu.regs.pc, 0x0004);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // rl (ix+d),b
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x03);
+        assert_eq!(cpu.cu.regs.main.b(), 0x03);
+        assert_eq!(cpu.clock.borrow().read(), 23);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // bit 0,(iy+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010000);
+        assert_eq!(cpu.cu.regs.wz, 0x0101);
+        assert_eq!(cpu.clock.borrow().read(), 20);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // res 0,(iy+d),a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0101), 0x02);
+        assert_eq!(cpu.cu.regs.main.a(), 0x02);
+        assert_eq!(cpu.clock.borrow().read(), 23);
+        assert_eq!(cpu.cu.regs.pc, 0x0010);
+    }
+
+    #[test]
+    fn test_ldir() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0xB0]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x01, 0x02, 0x03]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_de(0x0200);
+        cpu.cu.regs.main.set_bc(0x0003);
+
+        let res = cpu.execute(); // ldir, first iteration
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0200), 0x01);
+        assert_eq!(cpu.cu.regs.pc, 0x0000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000100);
+        assert_eq!(cpu.clock.borrow().read(), 21);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // last iteration
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0202), 0x03);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x0000);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0103);
+        assert_eq!(cpu.cu.regs.main.de(), 0x0203);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00100000);
+        assert_eq!(cpu.clock.borrow().read(), 37);
+    }
+
+    #[test]
+    fn test_ldd() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0xA8]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x55]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_de(0x0200);
+        cpu.cu.regs.main.set_bc(0x0001);
+
+        let res = cpu.execute(); // ldd
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0200), 0x55);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x00FF);
+        assert_eq!(cpu.cu.regs.main.de(), 0x01FF);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x0000);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+    }
+
+    #[test]
+    fn test_cpir() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0xB1]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x10, 0x20, 0x30]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_bc(0x0003);
+        cpu.cu.regs.main.set_a(0x20);
+
+        let res = cpu.execute(); // cpir, no match yet
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000110);
+        assert_eq!(cpu.clock.borrow().read(), 21);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // cpir, match
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0102);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x0001);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000110);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+    }
+
+    #[test]
+    fn test_otir() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0xB3, 0xED, 0xA2]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x01, 0x02]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_bc(0x02FE);
+
+        let res = cpu.execute(); // otir
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0000);
+        assert_eq!(cpu.cu.regs.main.b(), 0x01);
+        assert_eq!(cpu.clock.borrow().read(), 21);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.cu.regs.main.b(), 0x00);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0102);
+        assert!(cpu.cu.regs.main.get_flag(crate::cpu::regs::Flag::Z));
+        assert_eq!(cpu.clock.borrow().read(), 16);
+        cpu.clock.borrow_mut().reset();
+
+        cpu.cu.regs.main.set_b(0x01);
+        let res = cpu.execute(); // ini
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0102), 0xFF);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0103);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+    }
+
+    #[test]
+    fn test_neg() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x44]);
+        cpu.cu.regs.main.set_a(0x01);
+
+        let res = cpu.execute(); // neg
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0xFF);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10111011);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_im_ld_i_r() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x5E, 0xED, 0x47, 0xED, 0x4F, 0xED, 0x56]);
+        cpu.cu.regs.main.set_a(0x42);
+
+        let res = cpu.execute(); // im 2
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.im, 2);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld i,a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.i, 0x42);
+        assert_eq!(cpu.clock.borrow().read(), 9);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld r,a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.r, 0x42);
+        assert_eq!(cpu.clock.borrow().read(), 9);
+
+        let res = cpu.execute(); // im 1
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.im, 1);
+    }
+
+    #[test]
+    fn test_rld_rrd() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x6F, 0xED, 0x67]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x34]);
+        cpu.cu.regs.main.set_hl(0x0100);
+        cpu.cu.regs.main.set_a(0x12);
+
+        let res = cpu.execute(); // rld
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x13);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x42);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000000);
+        assert_eq!(cpu.clock.borrow().read(), 18);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // rrd
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x12);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x34);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000100);
+        assert_eq!(cpu.clock.borrow().read(), 18);
+    }
+
+    #[test]
+    fn test_adc_sbc_hl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x4A, 0xED, 0x52]);
+        cpu.cu.regs.main.set_hl(0x7FFF);
+        cpu.cu.regs.main.set_bc(0x0000);
+        cpu.cu.regs.main.set_f(0b00000001);
+
+        let res = cpu.execute(); // adc hl,bc
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x8000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10010100);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+        cpu.clock.borrow_mut().reset();
+
+        cpu.cu.regs.main.set_de(0x8000);
+        let res = cpu.execute(); // sbc hl,de
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000010);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+    }
+
+    #[test]
+    fn test_add_hl_inc_dec_rr() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x29, 0x03, 0x3B, 0xDD, 0x09, 0xFD, 0x23]);
+        cpu.cu.regs.main.set_hl(0x8800);
+        cpu.cu.regs.main.set_bc(0xFFFF);
+        cpu.cu.regs.ix = 0x1000;
+
+        let res = cpu.execute(); // add hl,hl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x1000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010001);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // inc bc
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x0000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010001);
+        assert_eq!(cpu.clock.borrow().read(), 6);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // dec sp
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.sp, 0xFFFF);
+        assert_eq!(cpu.clock.borrow().read(), 6);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // add ix,bc
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.ix, 0x1000);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000000);
+        assert_eq!(cpu.clock.borrow().read(), 15);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // inc iy
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.iy, 0x0001);
+        assert_eq!(cpu.clock.borrow().read(), 10);
+    }
+
+    #[test]
+    fn test_unknown_ed_is_nop() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x00, 0xED, 0xFF]);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0002);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0004);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+    }
+
+    #[test]
+    fn test_index_register_halves() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xDD, 0x44, 0xDD, 0x6F, 0xFD, 0x26, 0x99, 0xDD, 0x24, 0xDD, 0x85]);
+        cpu.cu.regs.ix = 0x1234;
+        cpu.cu.regs.main.set_hl(0xAAAA);
+        cpu.cu.regs.main.set_a(0x56);
+
+        let res = cpu.execute(); // ld b,ixh
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.b(), 0x12);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld ixl,a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.ix, 0x1256);
+        assert_eq!(cpu.cu.regs.main.hl(), 0xAAAA);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ld iyh,n
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.iy, 0x9900);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // inc ixh
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.ix, 0x1356);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+
+        let res = cpu.execute(); // add a,ixl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0xAC);
+        assert_eq!(cpu.cu.regs.main.hl(), 0xAAAA);
+    }
+
+    #[test]
+    fn test_index_displacement_uses_real_hl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xDD, 0x66, 0x01, 0xDD, 0x75, 0x02]);
+        cpu.bus.borrow_mut().write_vec(0x0100, vec![0x00, 0x77]);
+        cpu.cu.regs.ix = 0x0100;
+        cpu.cu.regs.main.set_hl(0x0011);
+
+        let res = cpu.execute(); // ld h,(ix+d)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.h(), 0x77);
+        assert_eq!(cpu.cu.regs.ix, 0x0100);
+
+        let res = cpu.execute(); // ld (ix+d),l
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0102), 0x11);
+    }
+
+    #[test]
+    fn test_di_ei() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xFB, 0xF3]);
+
+        let res = cpu.execute(); // ei
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(cpu.cu.regs.iff1);
+        assert!(cpu.cu.regs.iff2);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        let res = cpu.execute(); // di
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(!cpu.cu.regs.iff1);
+        assert!(!cpu.cu.regs.iff2);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+    }
+
+    #[test]
+    fn test_im1_interrupt_after_ei_delay() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x56, 0xFB, 0x00, 0x00]);
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.raise_int(0xFF);
+
+        let res = cpu.execute(); // im 1, interrupts disabled
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // ei
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // nop, still delayed by ei
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0004);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // interrupt acknowledge
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0038);
+        assert_eq!(cpu.cu.regs.sp, 0x0EFE);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0x04);
+        assert!(!cpu.cu.regs.iff1);
+        assert!(!cpu.cu.regs.iff2);
+        assert_eq!(cpu.clock.borrow().read(), 13);
+    }
+
+    #[test]
+    fn test_im0_interrupt() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x00]);
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.cu.regs.iff1 = true;
+        cpu.raise_int(0xCF);
+
+        let res = cpu.execute(); // rst 08h from the data bus
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0008);
+        assert_eq!(cpu.cu.regs.sp, 0x0EFE);
+        assert_eq!(cpu.clock.borrow().read(), 13);
+    }
+
+    #[test]
+    fn test_im0_prefix_rejected() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x00]);
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.cu.regs.iff1 = true;
+
+        for prefix in [0xCB, 0xDD, 0xED, 0xFD] {
+            cpu.raise_int(prefix);
+            let res = cpu.execute();
+            assert!(res.is_err(), "{:?}", res);
+            assert!(cpu.cu.prefix.is_none());
+            assert!(cpu.cu.regs.iff1);
+        }
+
+        cpu.release_int();
+        let res = cpu.execute(); // nop runs unprefixed
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_im2_interrupt() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x01FE, vec![0x00, 0x03]);
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.cu.regs.i = 0x01;
+        cpu.cu.regs.im = 2;
+        cpu.cu.regs.iff1 = true;
+        cpu.raise_int(0xFE);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0300);
+        assert_eq!(cpu.clock.borrow().read(), 19);
+
+        cpu.release_int();
+        cpu.cu.regs.iff1 = true;
+        let res = cpu.execute(); // nop at 0x0300
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0301);
+    }
+
+    #[test]
+    fn test_interrupt_exits_halt() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x76]);
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.cu.regs.im = 1;
+        cpu.cu.regs.iff1 = true;
+
+        let res = cpu.execute(); // halt
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.cu.regs.pc, 0x0001);
+
+        cpu.raise_int(0xFF);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.cu.regs.pc, 0x0038);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0x01);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0066, vec![0xED, 0x45]);
+        cpu.cu.regs.pc = 0x0100;
+        cpu.cu.regs.sp = 0x0F00;
+        cpu.cu.regs.iff1 = true;
+        cpu.cu.regs.iff2 = true;
+        cpu.trigger_nmi();
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0066);
+        assert!(!cpu.cu.regs.iff1);
+        assert!(cpu.cu.regs.iff2);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+
+        let res = cpu.execute(); // retn
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0100);
+        assert!(cpu.cu.regs.iff1);
+    }
+
+    #[test]
+    fn test_in_out_n() {
+        let mut cpu = init();
+        let written = add_test_port(&cpu, 0x80);
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xD3, 0xFE, 0xDB, 0xFE]);
+        cpu.cu.regs.main.set_a(0x07);
+        cpu.cu.regs.main.set_f(0b00000000);
+
+        let res = cpu.execute(); // out (n),a
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(*written.borrow(), vec![(0x07FE, 0x07)]);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // in a,(n)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x87);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00000000);
+        assert_eq!(cpu.clock.borrow().read(), 11);
+    }
+
+    #[test]
+    fn test_in_out_c() {
+        let mut cpu = init();
+        let written = add_test_port(&cpu, 0x00);
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x50, 0xED, 0x70, 0xED, 0x59, 0xED, 0x71]);
+        cpu.cu.regs.main.set_bc(0x81FE);
+        cpu.cu.regs.main.set_e(0x42);
+        cpu.cu.regs.main.set_f(0b00000001);
+
+        let res = cpu.execute(); // in d,(c)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.d(), 0x81);
+        assert_eq!(cpu.cu.regs.main.f(), 0b10000101);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+        cpu.clock.borrow_mut().reset();
+
+        cpu.cu.regs.main.set_b(0x00);
+        let res = cpu.execute(); // in f,(c)
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b01000101);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+
+        let res = cpu.execute(); // out (c),e
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // out (c),0
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(*written.borrow(), vec![(0x00FE, 0x42), (0x00FE, 0x00)]);
+        assert_eq!(cpu.clock.borrow().read(), 36);
+    }
+
+    #[test]
+    fn test_block_io_uses_ports() {
+        let mut cpu = init();
+        let written = add_test_port(&cpu, 0x10);
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0xA2, 0xED, 0xAB]);
+        cpu.cu.regs.main.set_bc(0x02FE);
+        cpu.cu.regs.main.set_hl(0x0100);
+
+        let res = cpu.execute(); // ini
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0100), 0x12);
+        assert_eq!(cpu.cu.regs.main.b(), 0x01);
+
+        let res = cpu.execute(); // outd
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(*written.borrow(), vec![(0x00FE, 0x00)]);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0100);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x08, 0xD9, 0xEB, 0xDD, 0xEB]);
+        cpu.cu.regs.main.set_af(0x1122);
+        cpu.cu.regs.alt.set_af(0x3344);
+        cpu.cu.regs.main.set_bc(0x5566);
+        cpu.cu.regs.alt.set_hl(0x7788);
+        cpu.cu.regs.ix = 0x99AA;
+
+        let res = cpu.execute(); // ex af,af'
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.af(), 0x3344);
+        assert_eq!(cpu.cu.regs.alt.af(), 0x1122);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        let res = cpu.execute(); // exx
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.bc(), 0x0000);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x7788);
+        assert_eq!(cpu.cu.regs.alt.bc(), 0x5566);
+        assert_eq!(cpu.cu.regs.main.af(), 0x3344);
+        assert_eq!(cpu.clock.borrow().read(), 8);
+
+        let res = cpu.execute(); // ex de,hl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.de(), 0x7788);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x0000);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+
+        let res = cpu.execute(); // ex de,hl ignores the prefix
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x7788);
+        assert_eq!(cpu.cu.regs.ix, 0x99AA);
+    }
+
+    #[test]
+    fn test_ex_sp_hl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xE3, 0xFD, 0xE3]);
+        cpu.bus.borrow_mut().write_vec(0x0EFE, vec![0x34, 0x12]);
+        cpu.cu.regs.sp = 0x0EFE;
+        cpu.cu.regs.main.set_hl(0x5678);
+        cpu.cu.regs.iy = 0x9ABC;
+
+        let res = cpu.execute(); // ex (sp),hl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.hl(), 0x1234);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0x78);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFF), 0x56);
+        assert_eq!(cpu.cu.regs.sp, 0x0EFE);
+        assert_eq!(cpu.clock.borrow().read(), 19);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // ex (sp),iy
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.iy, 0x5678);
+        assert_eq!(cpu.bus.borrow().peek(0x0EFE), 0xBC);
+        assert_eq!(cpu.clock.borrow().read(), 23);
+    }
+
+    #[test]
+    fn test_scf_ccf_cpl() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x37, 0x3F, 0x2F]);
+        cpu.cu.regs.main.set_a(0x28);
+
+        let res = cpu.execute(); // scf
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00101001);
+
+        let res = cpu.execute(); // ccf
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00111000);
+
+        let res = cpu.execute(); // cpl
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0xD7);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010010);
+        assert_eq!(cpu.clock.borrow().read(), 12);
+    }
+
+    #[test]
+    fn test_daa() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x3E, 0x15, 0xC6, 0x27, 0x27, 0xD6, 0x15, 0x27]);
+
+        let res = cpu.execute(); // ld a,15h
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // add a,27h
+        assert!(res.is_ok(), "{:?}", res);
+        cpu.clock.borrow_mut().reset();
+
+        let res = cpu.execute(); // daa after addition
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x42);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00010100);
+        assert_eq!(cpu.clock.borrow().read(), 4);
+
+        let res = cpu.execute(); // sub 15h
+        assert!(res.is_ok(), "{:?}", res);
+        let res = cpu.execute(); // daa after subtraction
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x27);
+        assert_eq!(cpu.cu.regs.main.f(), 0b00100110);
+    }
+
+    #[test]
+    fn test_rotate_a() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0x07, 0x1F, 0x17, 0x0F]);
+        cpu.cu.regs.main.set_a(0x81);
+        cpu.cu.regs.main.set_f(0b11000100);
+
+        let res = cpu.execute(); // rlca
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x03);
+        assert_eq!(cpu.cu.regs.main.f(), 0b11000101);
+
+        let res = cpu.execute(); // rra
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x81);
+        assert_eq!(cpu.cu.regs.main.f(), 0b11000101);
+
+        let res = cpu.execute(); // rla
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x03);
+        assert_eq!(cpu.cu.regs.main.f(), 0b11000101);
+
+        let res = cpu.execute(); // rrca
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x81);
+        assert_eq!(cpu.cu.regs.main.f(), 0b11000101);
+        assert_eq!(cpu.clock.borrow().read(), 16);
+    }
+
+    #[test]
+    fn test_refresh_register() {
+        let mut cpu = init();
+        // nop; ld b,(ix+1); set 0,(iy+1); ld a,r
+        cpu.bus.borrow_mut().write_vec(
+            0x0000,
+            vec![0x00, 0xDD, 0x46, 0x01, 0xFD, 0xCB, 0x01, 0xC6, 0xED, 0x5F],
+        );
+        cpu.cu.regs.r = 0xFE;
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.r, 0xFF);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.r, 0x81);
+
+        // the displacement and opcode of DDCB/FDCB are not M1 cycles
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.r, 0x83);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.main.a(), 0x85);
+    }
+
+    #[test]
+    fn test_memptr() {
+        let mut cpu = init();
+        cpu.bus.borrow_mut().write_vec(
+            0x0000,
+            vec![
+                0x3A, 0x00, 0x02, // ld a,(0200h)
+                0x32, 0xFF, 0x02, // ld (02FFh),a
+                0x2A, 0x10, 0x02, // ld hl,(0210h)
+                0xCA, 0x34, 0x12, // jp z,1234h
+                0xCD, 0x20, 0x00, // call 0020h
+            ],
+        );
+        cpu.bus.borrow_mut().write(0x0200, 0x55);
+        cpu.cu.regs.sp = 0x0400;
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.wz, 0x0201);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.wz, 0x5500);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.wz, 0x0211);
+
+        cpu.cu.regs.main.reset_flag(Flag::Z);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x000C);
+        assert_eq!(cpu.cu.regs.wz, 0x1234);
+
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.wz, 0x0020);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut cpu = init();
+        let summary = cpu.run_for(10);
+        assert_eq!(summary, super::RunSummary {
+            instructions: 3,
+            t_states: 12,
+            overshoot: 2,
+            reason: super::StopReason::Budget,
+        });
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut cpu = init();
+        // im 1; ei; halt; jr -3
+        cpu.bus.borrow_mut().write_vec(0x0000, vec![0xED, 0x56, 0xFB, 0x76, 0x18, 0xFD]);
+        // inc a; ei; ret
+        cpu.bus.borrow_mut().write_vec(0x0038, vec![0x3C, 0xFB, 0xC9]);
+        cpu.cu.regs.sp = 0x0800;
+
+        let summary = cpu.run_frame();
+        assert_eq!(summary.reason, super::StopReason::Budget);
+        assert_eq!(summary.t_states, 69888 + summary.overshoot);
+        assert!(summary.overshoot < 4);
+        // the interrupt is taken once: INT is released before the handler's EI
+        assert_eq!(cpu.cu.regs.main.a(), 1);
+        assert!(cpu.is_halted());
+
+        let summary = cpu.run_frame();
+        assert_eq!(cpu.clock.borrow().read(), 69888 * 2 + summary.overshoot as u64);
+        assert_eq!(cpu.clock.borrow().frames(), 2);
+        assert_eq!(cpu.cu.regs.main.a(), 2);
+    }
+
+    #[test]
+    fn test_set_model() {
+        let mut cpu = init_contended();
+        cpu.set_model(Model::Spectrum128K);
+        assert_eq!(cpu.clock.borrow().frame_length(), 70908);
+        assert_eq!(cpu.bus.borrow().contention().map(|contention| contention.model()), Some(Model::Spectrum128K));
+
+        let summary = cpu.run_frame();
+        assert_eq!(summary.t_states, 70908 + summary.overshoot);
+    }
+
+    #[test]
+    fn test_model_mismatch() {
+        let mut cpu = init();
+        cpu.clock.borrow_mut().set_frame_length(Model::Spectrum128K.t_states_per_frame());
+        let summary = cpu.run_frame();
+        assert_eq!(summary.reason, super::StopReason::Error("Clock frame length 70908 does not match the Spectrum48K".to_string()));
+        assert_eq!(summary.instructions, 0);
+        assert_eq!(cpu.clock.borrow().read(), 0);
+
+        cpu.clock.borrow_mut().set_frame_length(Model::Spectrum48K.t_states_per_frame());
+        cpu.bus.borrow_mut().set_contention(Contention::new(Model::Spectrum128K, cpu.clock.clone()));
+        let summary = cpu.run_frame();
+        assert_eq!(summary.reason, super::StopReason::Error("Contention does not match the Spectrum48K".to_string()));
+    }
+
+    fn init_contended() -> Cpu {
+        let bus: RefBus = Rc::new(RefCell::new(Bus::new()));
+        let clock: RefClock = Rc::new(RefCell::new(Clock::new()));
+        bus.borrow_mut().add_device(Box::new(Ram::new(0x4000, 0xC000, clock.clone()))).unwrap();
+        bus.borrow_mut().set_contention(Contention::new(Model::Spectrum48K, clock.clone()));
+
+        Cpu::new(bus, clock)
+    }
+
+    /// T-states taken by the instruction at `pc` when started at frame
+    /// T-state `start`.
+    fn timed(cpu: &mut Cpu, pc: u16, start: u32) -> u64 {
+        cpu.clock.borrow_mut().reset();
+        cpu.clock.borrow_mut().add(start);
+        cpu.cu.regs.pc = pc;
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        cpu.clock.borrow().read() - start as u64
+    }
+
+    #[test]
+    fn test_contended_memory_timing() {
+        let mut cpu = init_contended();
+        cpu.bus.borrow_mut().write_vec(0x8000, vec![0x7E, 0xC5, 0x34]); // ld a,(hl); push bc; inc (hl)
+        cpu.bus.borrow_mut().write_vec(0x7FFD, vec![0xDD, 0x7E, 0x00]); // ld a,(ix+0)
+        cpu.cu.regs.main.set_hl(0x4000);
+        cpu.cu.regs.ix = 0x8000;
+        cpu.cu.regs.sp = 0x9000;
+
+        // pc:4, hl:3 with the read at 14335 delayed by 6
+        assert_eq!(timed(&mut cpu, 0x8000, 14330), 7);
+        assert_eq!(timed(&mut cpu, 0x8000, 14331), 13);
+        assert_eq!(timed(&mut cpu, 0x8000, 14332), 12);
+
+        // pc:4, IR:1, sp-1:3, sp-2:3 with IR contended
+        cpu.cu.regs.i = 0x40;
+        assert_eq!(timed(&mut cpu, 0x8001, 14331), 17);
+        cpu.cu.regs.i = 0x00;
+        assert_eq!(timed(&mut cpu, 0x8001, 14331), 11);
+
+        // pc:4, hl:3, hl:1, hl:3 with the internal T-state at 14335
+        assert_eq!(timed(&mut cpu, 0x8002, 14328), 17);
+
+        // pc:4, pc+1:4, pc+2:3, pc+2:1 x5, ii+n:3 with the last internal
+        // T-state at 14335
+        assert_eq!(timed(&mut cpu, 0x7FFD, 14320), 25);
+    }
+
+    #[test]
+    fn test_contended_io_timing() {
+        let mut cpu = init_contended();
+        cpu.bus.borrow_mut().write_vec(0x8000, vec![0xD3, 0xFE, 0xED, 0x78]); // out (feh),a; in a,(c)
+        cpu.cu.regs.main.set_a(0x00);
+
+        // pc:4, pc+1:3, then N:1 C:3 with the C at 14335
+        assert_eq!(timed(&mut cpu, 0x8000, 14326), 11);
+        assert_eq!(timed(&mut cpu, 0x8000, 14327), 17);
+
+        // pc:4, pc+1:4, then N:4 or C:1 C:1 C:1 C:1 from 14335
+        cpu.cu.regs.main.set_bc(0x00FF);
+        assert_eq!(timed(&mut cpu, 0x8002, 14327), 12);
+        cpu.cu.regs.main.set_bc(0x40FF);
+        assert_eq!(timed(&mut cpu, 0x8002, 14327), 24);
+        // C:1 C:3
+        cpu.cu.regs.main.set_bc(0x40FE);
+        assert_eq!(timed(&mut cpu, 0x8002, 14327), 18);
+    }
+
+    fn fast_load_setup(cpu: &mut Cpu, flag: u8, length: u16, load: bool) {
+        cpu.bus.borrow_mut().write_vec(0x0556, vec![0x14, 0x08, 0x15]);
+        cpu.cu.regs.pc = 0x0556;
+        cpu.cu.regs.sp = 0x0800;
+        cpu.bus.borrow_mut().write_vec(0x0800, vec![0x34, 0x12]);
+        cpu.cu.regs.main.set_a(flag);
+        cpu.cu.regs.main.update_flag(Flag::C, load);
+        cpu.cu.regs.ix = 0x0400;
+        cpu.cu.regs.main.set_de(length);
+    }
+
+    #[test]
+    fn test_fast_load() {
+        let mut cpu = init();
+        let tap = crate::tape::tap::Tap::parse(&[
+            0x05, 0x00, 0xFF, 0x01, 0x02, 0x03, 0xFF,
+            0x03, 0x00, 0xFF, 0xAA, 0x00,
+        ]).unwrap();
+        cpu.insert_tape(tap);
+
+        fast_load_setup(&mut cpu, 0xFF, 3, true);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0400), 0x01);
+        assert_eq!(cpu.bus.borrow().peek(0x0402), 0x03);
+        assert_eq!(cpu.cu.regs.ix, 0x0403);
+        assert_eq!(cpu.cu.regs.main.de(), 0);
+        assert!(cpu.cu.regs.main.get_flag(Flag::C));
+        assert_eq!(cpu.cu.regs.pc, 0x1234);
+        assert_eq!(cpu.cu.regs.sp, 0x0802);
+        assert_eq!(cpu.clock.borrow().read(), 0);
+
+        // bad checksum: the data is loaded but carry comes back reset
+        fast_load_setup(&mut cpu, 0xFF, 1, true);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0400), 0xAA);
+        assert!(!cpu.cu.regs.main.get_flag(Flag::C));
+
+        // tape over: the ROM runs
+        fast_load_setup(&mut cpu, 0xFF, 1, true);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0557);
+    }
+
+    #[test]
+    fn test_fast_load_into_rom() {
+        let bus: RefBus = Rc::new(RefCell::new(Bus::new()));
+        let clock: RefClock = Rc::new(RefCell::new(Clock::new()));
+        let mut rom = vec![0x00; 0x4000];
+        rom[0x0556..0x0559].copy_from_slice(&[0x14, 0x08, 0x15]);
+        bus.borrow_mut().add_device(Box::new(Rom::from_bytes(0x0000, 0x4000, &rom, clock.clone()).unwrap())).unwrap();
+        bus.borrow_mut().add_device(Box::new(Ram::new(0x4000, 0xC000, clock.clone()))).unwrap();
+        let mut cpu = Cpu::new(bus, clock);
+        let tap = crate::tape::tap::Tap::parse(&[0x05, 0x00, 0xFF, 0x01, 0x02, 0x03, 0xFF]).unwrap();
+        cpu.insert_tape(tap);
+
+        fast_load_setup(&mut cpu, 0xFF, 3, true);
+        cpu.bus.borrow_mut().write_vec(0x9000, vec![0x34, 0x12]);
+        cpu.cu.regs.sp = 0x9000;
+        cpu.cu.regs.ix = 0x3FFE;
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x3FFE), 0x00);
+        assert_eq!(cpu.bus.borrow().peek(0x3FFF), 0x00);
+        assert_eq!(cpu.bus.borrow().peek(0x4000), 0x03);
+        assert!(cpu.cu.regs.main.get_flag(Flag::C));
+        assert_eq!(cpu.cu.regs.pc, 0x1234);
+    }
+
+    #[test]
+    fn test_fast_load_other_rom() {
+        let mut cpu = init();
+        let tap = crate::tape::tap::Tap::parse(&[0x03, 0x00, 0xFF, 0xAA, 0x55]).unwrap();
+        cpu.insert_tape(tap);
+
+        // some other ROM paged in, such as the 128K editor: no trap
+        fast_load_setup(&mut cpu, 0xFF, 1, true);
+        cpu.bus.borrow_mut().write_vec(0x0556, vec![0x00, 0x00, 0x00]);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.cu.regs.pc, 0x0557);
+        assert_eq!(cpu.bus.borrow().peek(0x0400), 0x00);
+
+        // the block is still there for LD-BYTES
+        fast_load_setup(&mut cpu, 0xFF, 1, true);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(cpu.bus.borrow().peek(0x0400), 0xAA);
+    }
+
+    #[test]
+    fn test_fast_verify() {
+        let mut cpu = init();
+        let tap = crate::tape::tap::Tap::parse(&[
+            0x03, 0x00, 0x00, 0x55, 0x55,
+            0x03, 0x00, 0xFF, 0x55, 0xAA,
+            0x03, 0x00, 0xFF, 0x55, 0xAA,
+        ]).unwrap();
+        cpu.insert_tape(tap);
+
+        // wrong flag
+        fast_load_setup(&mut cpu, 0xFF, 1, true);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(!cpu.cu.regs.main.get_flag(Flag::C));
+        assert_eq!(cpu.bus.borrow().peek(0x0400), 0x00);
+
+        fast_load_setup(&mut cpu, 0xFF, 1, false);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(!cpu.cu.regs.main.get_flag(Flag::C));
+
+        cpu.bus.borrow_mut().write_vec(0x0400, vec![0x55]);
+        fast_load_setup(&mut cpu, 0xFF, 1, false);
+        let res = cpu.execute();
+        assert!(res.is_ok(), "{:?}", res);
+        assert!(cpu.cu.regs.main.get_flag(Flag::C));
+    }
+}
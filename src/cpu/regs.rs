@@ -1,5 +1,6 @@
+#[derive(Clone, Copy)]
 pub enum Flag {
-    S,Z,H,PV,N,C
+    S,Z,Y,H,X,PV,N,C
 }
 
 impl Flag {
@@ -7,7 +8,9 @@ impl Flag {
         match self {
             Flag::S => 7,
             Flag::Z => 6,
+            Flag::Y => 5,
             Flag::H => 4,
+            Flag::X => 3,
             Flag::PV => 2,
             Flag::N => 1,
             Flag::C => 0,
@@ -146,14 +149,22 @@ impl RegisterSet {
     }
 
     pub fn set_flag(&mut self, flag: Flag) {
-        let mask = (1 as u8) << flag.get_bit();
+        let mask = 1_u8 << flag.get_bit();
         self.f |= mask;
     }
 
     pub fn reset_flag(&mut self, flag: Flag) {
-        let mask = (1 as u8) << flag.get_bit();
+        let mask = 1_u8 << flag.get_bit();
         self.f &= !mask;
     }
+
+    pub fn update_flag(&mut self, flag: Flag, value: bool) {
+        if value { self.set_flag(flag) } else { self.reset_flag(flag) }
+    }
+
+    pub fn get_flag(&self, flag: Flag) -> bool {
+        self.f & (1_u8 << flag.get_bit()) != 0
+    }
 }
 
 #[derive(Debug)]
@@ -166,8 +177,16 @@ pub struct Registers {
     pub iy: u16,
     pub i: u8,
     pub r: u8,
+    pub wz: u16,
     pub iff1: bool,
-    pub iff2: bool
+    pub iff2: bool,
+    pub im: u8,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Registers {
@@ -181,10 +200,36 @@ impl Registers {
             iy: 0,
             i: 0,
             r: 0,
+            wz: 0,
             iff1: false,
             iff2: false,
+            im: 0,
         }
     }
+
+    /// Memory refresh: every M1 cycle increments the low 7 bits of R, bit 7
+    /// only changes through LD R,A.
+    pub fn increment_r(&mut self) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+    }
+
+    /// EX AF,AF'
+    pub fn swap_af(&mut self) {
+        let af = self.main.af();
+        self.main.set_af(self.alt.af());
+        self.alt.set_af(af);
+    }
+
+    /// EXX: swaps BC, DE and HL with their shadow copies.
+    pub fn swap_main(&mut self) {
+        let (bc, de, hl) = (self.main.bc(), self.main.de(), self.main.hl());
+        self.main.set_bc(self.alt.bc());
+        self.main.set_de(self.alt.de());
+        self.main.set_hl(self.alt.hl());
+        self.alt.set_bc(bc);
+        self.alt.set_de(de);
+        self.alt.set_hl(hl);
+    }
 }
 
 #[cfg(test)]
@@ -226,5 +271,48 @@ mod test_register {
         
         bank.main.reset_flag(super::Flag::PV);
         assert_eq!(bank.main.f(), 0b00010011);
+
+        bank.main.set_flag(super::Flag::Y);
+        bank.main.set_flag(super::Flag::X);
+        assert_eq!(bank.main.f(), 0b00111011);
+        assert!(bank.main.get_flag(super::Flag::Y));
+
+        bank.main.update_flag(super::Flag::X, false);
+        assert_eq!(bank.main.f(), 0b00110011);
+        assert!(!bank.main.get_flag(super::Flag::X));
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut bank = Registers::new();
+        bank.main.set_af(0x1122);
+        bank.main.set_bc(0x3344);
+        bank.alt.set_af(0x5566);
+        bank.alt.set_hl(0x7788);
+
+        bank.swap_af();
+        assert_eq!(bank.main.af(), 0x5566);
+        assert_eq!(bank.alt.af(), 0x1122);
+        assert_eq!(bank.main.bc(), 0x3344);
+
+        bank.swap_main();
+        assert_eq!(bank.main.bc(), 0x0000);
+        assert_eq!(bank.main.hl(), 0x7788);
+        assert_eq!(bank.alt.bc(), 0x3344);
+        assert_eq!(bank.main.af(), 0x5566);
+    }
+
+    #[test]
+    fn test_increment_r() {
+        let mut bank = Registers::new();
+        bank.r = 0x7F;
+        bank.increment_r();
+        assert_eq!(bank.r, 0x00);
+
+        bank.r = 0xFF;
+        bank.increment_r();
+        assert_eq!(bank.r, 0x80);
+        bank.increment_r();
+        assert_eq!(bank.r, 0x81);
     }
 }
\ No newline at end of file
@@ -0,0 +1,456 @@
+use crate::{audio::AudioBuffer, bus::IoDevice, cpu::RefClock, model::Model};
+
+const VOLUME: f32 = 0.5;
+
+/// T-states per tick of the tone counters: the AY runs at half the CPU clock
+/// and its counters at an eighth of that.
+const T_STATES_PER_TICK: u64 = 16;
+
+/// Output level of the logarithmic DAC for each volume.
+const LEVELS: [f32; 16] = [
+    0.0, 0.0137, 0.0205, 0.0291, 0.0423, 0.0618, 0.0847, 0.1369,
+    0.1691, 0.2647, 0.3527, 0.4499, 0.5704, 0.6873, 0.8482, 1.0,
+];
+
+/// Significant bits of each register, unused ones read back as 0.
+const MASKS: [u8; 16] = [
+    0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF,
+    0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF,
+];
+
+const MIXER: usize = 7;
+const ENVELOPE_SHAPE: usize = 13;
+
+/// How the three channels are placed in the stereo image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoMode {
+    Mono,
+    /// A left, B centre, C right.
+    Abc,
+    /// A left, C centre, B right.
+    Acb,
+}
+
+impl StereoMode {
+    /// Left and right weights of each channel, each side adding up to 1.
+    fn weights(&self) -> [(f32, f32); 3] {
+        const SIDE: f32 = 2.0 / 3.0;
+        const CENTRE: f32 = 1.0 / 3.0;
+        match self {
+            StereoMode::Mono => [(CENTRE, CENTRE); 3],
+            StereoMode::Abc => [(SIDE, 0.0), (CENTRE, CENTRE), (0.0, SIDE)],
+            StereoMode::Acb => [(SIDE, 0.0), (0.0, SIDE), (CENTRE, CENTRE)],
+        }
+    }
+}
+
+/// AY-3-8912 sound generator of the 128K models, on ports 0xFFFD (register
+/// select, and read) and 0xBFFD (register write).
+///
+/// The chip is clocked lazily from the CPU clock: register writes first
+/// catch it up to the time of the write, and `render` catches it up and mixes
+/// the samples into an `AudioBuffer` on the same sample grid as the beeper.
+pub struct Ay {
+    clock: RefClock,
+    registers: [u8; 16],
+    /// None while an address outside the chip's 16 registers is latched.
+    selected: Option<usize>,
+    stereo: StereoMode,
+
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u16,
+    noise_shift: u32,
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_hold: Option<u8>,
+    /// Noise and envelope advance every other tick.
+    prescaler: bool,
+
+    t_states_per_sample: f64,
+    next_tick: u64,
+    next_sample: f64,
+    pending: Vec<(f32, f32)>,
+}
+
+impl Ay {
+    pub fn new(model: Model, clock: RefClock, sample_rate: u32) -> Self {
+        let mut ay = Self {
+            clock,
+            registers: [0; 16],
+            selected: Some(0),
+            stereo: StereoMode::Abc,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_shift: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_hold: Some(0),
+            prescaler: false,
+            t_states_per_sample: model.cpu_hz() as f64 / sample_rate as f64,
+            next_tick: 0,
+            next_sample: 0.0,
+            pending: vec![],
+        };
+        ay.reset();
+        ay
+    }
+
+    /// Clears the registers and restarts sampling at the current clock time.
+    pub fn reset(&mut self) {
+        self.registers = [0; 16];
+        self.selected = Some(0);
+        self.tone_counters = [0; 3];
+        self.tone_outputs = [false; 3];
+        self.noise_counter = 0;
+        self.noise_shift = 1;
+        self.envelope_counter = 0;
+        self.envelope_step = 0;
+        self.envelope_attack = false;
+        self.envelope_hold = Some(0);
+        let now = self.clock.borrow().read();
+        self.next_tick = now;
+        self.next_sample = now as f64;
+        self.pending.clear();
+    }
+
+    pub fn set_stereo(&mut self, stereo: StereoMode) {
+        self.stereo = stereo;
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    pub fn register(&self, register: usize) -> u8 {
+        self.registers[register]
+    }
+
+    /// Latches a register address. The upper nibble is the chip address,
+    /// 0 on the AY-3-8912: any other value deselects the chip until a valid
+    /// register is selected again.
+    pub fn select(&mut self, register: u8) {
+        self.selected = (register < 0x10).then_some(register as usize);
+    }
+
+    /// Writes the selected register, if any. Writing the envelope shape
+    /// restarts the envelope.
+    pub fn write(&mut self, value: u8) {
+        let Some(selected) = self.selected else {
+            return;
+        };
+        self.update();
+        self.registers[selected] = value & MASKS[selected];
+        if selected == ENVELOPE_SHAPE {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_attack = value & 0x04 != 0;
+            self.envelope_hold = None;
+        }
+    }
+
+    /// Mixes every sample completed by now into `buffer`, from its first
+    /// sample on. Returns the number of samples rendered.
+    pub fn render(&mut self, buffer: &mut AudioBuffer) -> usize {
+        self.update();
+        let rendered = self.pending.len();
+        for (index, (left, right)) in self.pending.drain(..).enumerate() {
+            buffer.mix(index, left, right);
+        }
+        rendered
+    }
+
+    /// Runs the chip up to the last sample completed by the clock.
+    fn update(&mut self) {
+        let now = self.clock.borrow().read();
+        if (now as f64) < self.next_sample - self.t_states_per_sample {
+            // The clock was reset under us.
+            self.next_tick = now;
+            self.next_sample = now as f64;
+        }
+
+        while self.next_sample + self.t_states_per_sample <= now as f64 {
+            let end = self.next_sample + self.t_states_per_sample;
+            let mut channels = [0.0; 3];
+            let mut ticks = 0;
+
+            while (self.next_tick as f64) < end {
+                self.tick();
+                for (channel, level) in channels.iter_mut().enumerate() {
+                    *level += self.channel_level(channel);
+                }
+                ticks += 1;
+                self.next_tick += T_STATES_PER_TICK;
+            }
+
+            let (mut left, mut right) = (0.0, 0.0);
+            if ticks > 0 {
+                for (level, (left_weight, right_weight)) in channels.iter().zip(self.stereo.weights()) {
+                    left += level / ticks as f32 * left_weight;
+                    right += level / ticks as f32 * right_weight;
+                }
+            }
+            self.pending.push((left * VOLUME, right * VOLUME));
+            self.next_sample = end;
+        }
+    }
+
+    fn tick(&mut self) {
+        for channel in 0..3 {
+            let period = self.tone_period(channel).max(1);
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= period {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.prescaler = !self.prescaler;
+        if !self.prescaler {
+            return;
+        }
+
+        let noise_period = (self.registers[6] as u16).max(1);
+        self.noise_counter += 1;
+        if self.noise_counter >= noise_period {
+            self.noise_counter = 0;
+            let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (bit << 16);
+        }
+
+        let envelope_period = (self.registers[11] as u32 | (self.registers[12] as u32) << 8).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter >= envelope_period {
+            self.envelope_counter = 0;
+            self.step_envelope();
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        self.registers[channel * 2] as u16 | (self.registers[channel * 2 + 1] as u16) << 8
+    }
+
+    /// Advances the envelope one of its 16 steps. At the end of a cycle the
+    /// shape bits (CONTINUE, ATTACK, ALTERNATE, HOLD) decide what follows.
+    fn step_envelope(&mut self) {
+        if self.envelope_hold.is_some() {
+            return;
+        }
+
+        self.envelope_step += 1;
+        if self.envelope_step < 16 {
+            return;
+        }
+
+        let shape = self.registers[ENVELOPE_SHAPE];
+        let last = if self.envelope_attack { 15 } else { 0 };
+        if shape & 0x08 == 0 {
+            self.envelope_hold = Some(0);
+        } else if shape & 0x01 != 0 {
+            self.envelope_hold = Some(if shape & 0x02 != 0 { 15 - last } else { last });
+        } else {
+            self.envelope_step = 0;
+            if shape & 0x02 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    fn envelope_volume(&self) -> u8 {
+        match self.envelope_hold {
+            Some(volume) => volume,
+            None if self.envelope_attack => self.envelope_step,
+            None => 15 - self.envelope_step,
+        }
+    }
+
+    fn channel_level(&self, channel: usize) -> f32 {
+        let mixer = self.registers[MIXER];
+        let tone = self.tone_outputs[channel] || mixer & (0x01 << channel) != 0;
+        let noise = self.noise_shift & 1 != 0 || mixer & (0x08 << channel) != 0;
+        if !(tone && noise) {
+            return 0.0;
+        }
+
+        let amplitude = self.registers[8 + channel];
+        let volume = if amplitude & 0x10 != 0 { self.envelope_volume() } else { amplitude & 0x0F };
+        LEVELS[volume as usize]
+    }
+}
+
+/// Decoded by A15 high and A1 low; A14 tells 0xFFFD from 0xBFFD.
+impl IoDevice for Ay {
+    fn get_port_mask(&self) -> u16 {
+        0x8002
+    }
+
+    fn get_port_match(&self) -> u16 {
+        0x8000
+    }
+
+    fn read_port(&mut self, port: u16) -> u8 {
+        match self.selected {
+            Some(selected) if port & 0x4000 != 0 => self.registers[selected],
+            _ => 0xFF,
+        }
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        if port & 0x4000 != 0 {
+            self.select(value);
+        } else {
+            self.write(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_ay {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{audio::AudioBuffer, bus::{Bus, IoDevice}, clock::Clock, cpu::RefClock, device::beeper::Beeper, model::Model};
+
+    use super::{Ay, StereoMode, LEVELS};
+
+    fn init() -> (Ay, RefClock) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        (Ay::new(Model::Spectrum128K, clock.clone(), 44100), clock)
+    }
+
+    fn set(ay: &mut Ay, register: u8, value: u8) {
+        ay.write_port(0xFFFD, register);
+        ay.write_port(0xBFFD, value);
+    }
+
+    #[test]
+    fn test_registers() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let ay = Rc::new(RefCell::new(Ay::new(Model::Spectrum128K, clock, 44100)));
+        let mut bus = Bus::new();
+        bus.add_io_device(Box::new(Rc::clone(&ay)));
+
+        bus.write_port(0xFFFD, 1);
+        bus.write_port(0xBFFD, 0xFF);
+        assert_eq!(bus.read_port(0xFFFD), 0x0F);
+        // a chip address in the upper nibble deselects the chip
+        bus.write_port(0xFFFD, 0x17);
+        assert_eq!(ay.borrow().selected(), None);
+        bus.write_port(0xBFFD, 0x38);
+        assert_eq!(bus.read_port(0xFFFD), 0xFF);
+        assert_eq!(ay.borrow().register(7), 0x00);
+
+        bus.write_port(0xFFFD, 0x07);
+        assert_eq!(ay.borrow().selected(), Some(7));
+        bus.write_port(0xBFFD, 0x38);
+        assert_eq!(bus.read_port(0xFFFD), 0x38);
+        assert_eq!(ay.borrow().register(1), 0x0F);
+
+        // 0x7FFD belongs to the memory paging
+        bus.write_port(0x7FFD, 0x00);
+        assert_eq!(ay.borrow().register(7), 0x38);
+    }
+
+    #[test]
+    fn test_tone() {
+        let (mut ay, _) = init();
+        set(&mut ay, 0, 3);
+        set(&mut ay, 7, 0b00111110); // tone A only
+        set(&mut ay, 8, 15);
+
+        let outputs: Vec<bool> = (0..12).map(|_| {
+            ay.tick();
+            ay.tone_outputs[0]
+        }).collect();
+        assert_eq!(outputs, vec![false, false, true, true, true, false, false, false, true, true, true, false]);
+        assert_eq!(ay.channel_level(1), LEVELS[0]);
+    }
+
+    #[test]
+    fn test_noise() {
+        let (mut ay, _) = init();
+        set(&mut ay, 6, 1);
+        let mut seen = vec![];
+        for _ in 0..64 {
+            ay.tick();
+            seen.push(ay.noise_shift & 1);
+        }
+        assert!(seen.contains(&0) && seen.contains(&1));
+    }
+
+    fn envelope(shape: u8) -> Vec<u8> {
+        let (mut ay, _) = init();
+        set(&mut ay, 11, 1);
+        set(&mut ay, 13, shape);
+        (0..48).map(|_| {
+            let volume = ay.envelope_volume();
+            ay.step_envelope();
+            volume
+        }).collect()
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let down: Vec<u8> = (0..16).rev().collect();
+        let up: Vec<u8> = (0..16).collect();
+
+        assert_eq!(envelope(0x00), [down.clone(), vec![0; 32]].concat());
+        assert_eq!(envelope(0x04), [up.clone(), vec![0; 32]].concat());
+        assert_eq!(envelope(0x08), [down.clone(), down.clone(), down.clone()].concat());
+        assert_eq!(envelope(0x09), [down.clone(), vec![0; 32]].concat());
+        assert_eq!(envelope(0x0A), [down.clone(), up.clone(), down.clone()].concat());
+        assert_eq!(envelope(0x0B), [down.clone(), vec![15; 32]].concat());
+        assert_eq!(envelope(0x0C), [up.clone(), up.clone(), up.clone()].concat());
+        assert_eq!(envelope(0x0D), [up.clone(), vec![15; 32]].concat());
+        assert_eq!(envelope(0x0E), [up.clone(), down.clone(), up.clone()].concat());
+        assert_eq!(envelope(0x0F), [up, vec![0; 32]].concat());
+    }
+
+    #[test]
+    fn test_render_stereo() {
+        let (mut ay, clock) = init();
+        set(&mut ay, 7, 0b00111111); // everything off: channels stay high
+        set(&mut ay, 8, 15);
+        clock.borrow_mut().add(1000);
+
+        let mut buffer = AudioBuffer::new(44100);
+        let rendered = ay.render(&mut buffer);
+        assert_eq!(rendered, (1000.0 / (3_546_900.0 / 44100.0)) as usize);
+        assert!((buffer.samples()[0] - 0.5 * 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(buffer.samples()[1], 0.0);
+
+        ay.set_stereo(StereoMode::Acb);
+        set(&mut ay, 8, 0);
+        set(&mut ay, 9, 15);
+        clock.borrow_mut().add(1000);
+        buffer.clear();
+        ay.render(&mut buffer);
+        assert_eq!(buffer.samples()[0], 0.0);
+        assert!((buffer.samples()[1] - 0.5 * 2.0 / 3.0).abs() < 1e-6);
+
+        ay.set_stereo(StereoMode::Mono);
+        clock.borrow_mut().add(1000);
+        buffer.clear();
+        ay.render(&mut buffer);
+        assert_eq!(buffer.samples()[0], buffer.samples()[1]);
+    }
+
+    #[test]
+    fn test_mix_with_beeper() {
+        let (mut ay, clock) = init();
+        let mut beeper = Beeper::new(Model::Spectrum128K, clock.clone(), 44100);
+        set(&mut ay, 7, 0b00111111);
+        set(&mut ay, 10, 15);
+        beeper.write_port(0x00FE, 0x10);
+        clock.borrow_mut().add(70908);
+
+        let mut buffer = AudioBuffer::new(44100);
+        let beeper_samples = beeper.render(&mut buffer);
+        assert_eq!(ay.render(&mut buffer), beeper_samples);
+        assert_eq!(buffer.len(), beeper_samples);
+        assert!((buffer.samples()[0] - 0.4).abs() < 1e-6);
+        assert!((buffer.samples()[1] - (0.4 + 0.5 * 2.0 / 3.0)).abs() < 1e-6);
+    }
+}
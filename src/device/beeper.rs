@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+use crate::{audio::AudioBuffer, bus::IoDevice, cpu::RefClock, model::Model};
+
+const VOLUME: f32 = 0.5;
+
+/// Speaker output level for the EAR (bit 4) and MIC (bit 3) bits written to
+/// port 0xFE. EAR drives the speaker, MIC only leaks a little into it.
+fn level(value: u8) -> f32 {
+    let ear = if value & 0x10 != 0 { 0.8 } else { 0.0 };
+    let mic = if value & 0x08 != 0 { 0.2 } else { 0.0 };
+    ear + mic
+}
+
+/// The beeper. Writes to port 0xFE are recorded with their clock time, and
+/// `render` turns them into samples: each one is the average speaker level
+/// over its period, a box filter that keeps the square waves from aliasing
+/// badly.
+pub struct Beeper {
+    clock: RefClock,
+    t_states_per_sample: f64,
+    /// Level changes not rendered yet, as (T-state, level).
+    edges: VecDeque<(u64, f32)>,
+    level: f32,
+    /// Start of the next sample, in T-states.
+    next_sample: f64,
+}
+
+impl Beeper {
+    pub fn new(model: Model, clock: RefClock, sample_rate: u32) -> Self {
+        let mut beeper = Self {
+            clock,
+            t_states_per_sample: model.cpu_hz() as f64 / sample_rate as f64,
+            edges: VecDeque::new(),
+            level: 0.0,
+            next_sample: 0.0,
+        };
+        beeper.reset();
+        beeper
+    }
+
+    /// Restarts sampling at the current clock time, dropping pending edges.
+    pub fn reset(&mut self) {
+        self.edges.clear();
+        self.next_sample = self.clock.borrow().read() as f64;
+    }
+
+    /// Renders every sample completed by now into `buffer`, from its first
+    /// sample on. Returns the number of samples rendered.
+    pub fn render(&mut self, buffer: &mut AudioBuffer) -> usize {
+        let now = self.clock.borrow().read() as f64;
+        if now < self.next_sample - self.t_states_per_sample {
+            // The clock was reset under us.
+            self.reset();
+        }
+
+        let mut index = 0;
+        while self.next_sample + self.t_states_per_sample <= now {
+            let start = self.next_sample;
+            let end = start + self.t_states_per_sample;
+            let mut t = start;
+            let mut sum = 0.0;
+
+            while let Some(&(at, level)) = self.edges.front() {
+                let at = at as f64;
+                if at >= end {
+                    break;
+                }
+                if at > t {
+                    sum += self.level as f64 * (at - t);
+                    t = at;
+                }
+                self.level = level;
+                self.edges.pop_front();
+            }
+            sum += self.level as f64 * (end - t);
+
+            let sample = (sum / self.t_states_per_sample) as f32 * VOLUME;
+            buffer.mix(index, sample, sample);
+            index += 1;
+            self.next_sample = end;
+        }
+        index
+    }
+}
+
+/// Listens to writes on every even port, like the ULA.
+impl IoDevice for Beeper {
+    fn get_port_mask(&self) -> u16 {
+        0x0001
+    }
+
+    fn get_port_match(&self) -> u16 {
+        0x0000
+    }
+
+    fn write_port(&mut self, _port: u16, value: u8) {
+        let at = self.clock.borrow().read();
+        self.edges.push_back((at, level(value)));
+    }
+}
+
+#[cfg(test)]
+mod test_beeper {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{audio::AudioBuffer, bus::IoDevice, clock::Clock, model::Model};
+
+    use super::{Beeper, VOLUME};
+
+    /// A sample rate making a sample exactly 100 T-states long.
+    const SAMPLE_RATE: u32 = 35_000;
+
+    #[test]
+    fn test_square_wave() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut beeper = Beeper::new(Model::Spectrum48K, clock.clone(), SAMPLE_RATE);
+        let mut buffer = AudioBuffer::new(SAMPLE_RATE);
+
+        for _ in 0..4 {
+            beeper.write_port(0x00FE, 0x10);
+            clock.borrow_mut().add(100);
+            beeper.write_port(0x00FE, 0x00);
+            clock.borrow_mut().add(100);
+        }
+        assert_eq!(beeper.render(&mut buffer), 8);
+        assert_eq!(buffer.len(), 8);
+        assert_eq!(buffer.samples()[0], 0.8 * VOLUME);
+        assert_eq!(buffer.samples()[1], 0.8 * VOLUME);
+        assert_eq!(buffer.samples()[2], 0.0);
+    }
+
+    #[test]
+    fn test_late_start() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        clock.borrow_mut().add(69888 * 10);
+        let mut beeper = Beeper::new(Model::Spectrum48K, clock.clone(), SAMPLE_RATE);
+        let mut buffer = AudioBuffer::new(SAMPLE_RATE);
+
+        // only the frame since the beeper was created is rendered
+        clock.borrow_mut().add(69888);
+        assert_eq!(beeper.render(&mut buffer), 698);
+    }
+
+    #[test]
+    fn test_box_filter() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut beeper = Beeper::new(Model::Spectrum48K, clock.clone(), SAMPLE_RATE);
+        let mut buffer = AudioBuffer::new(SAMPLE_RATE);
+
+        clock.borrow_mut().add(25);
+        beeper.write_port(0x00FE, 0x18);
+        clock.borrow_mut().add(50);
+        beeper.write_port(0x00FE, 0x00);
+        clock.borrow_mut().add(130);
+
+        assert_eq!(beeper.render(&mut buffer), 2);
+        assert!((buffer.samples()[0] - 0.5 * VOLUME).abs() < 1e-6);
+        assert_eq!(buffer.samples()[2], 0.0);
+
+        // the partial third sample is rendered with the next frame
+        buffer.clear();
+        beeper.write_port(0x00FE, 0x10);
+        clock.borrow_mut().add(95);
+        assert_eq!(beeper.render(&mut buffer), 1);
+        assert!((buffer.samples()[0] - 0.76 * VOLUME).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::bus::IoDevice;
+
+/// Keys of the Spectrum keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    CapsShift, Z, X, C, V,
+    A, S, D, F, G,
+    Q, W, E, R, T,
+    N1, N2, N3, N4, N5,
+    N0, N9, N8, N7, N6,
+    P, O, I, U, Y,
+    Enter, L, K, J, H,
+    Space, SymbolShift, M, N, B,
+}
+
+/// Keys in matrix order: half-row by half-row, bit 0 first. Half-row `n` is
+/// selected by address line A(8 + n) low.
+const MATRIX: [[Key; 5]; 8] = [
+    [Key::CapsShift, Key::Z, Key::X, Key::C, Key::V],
+    [Key::A, Key::S, Key::D, Key::F, Key::G],
+    [Key::Q, Key::W, Key::E, Key::R, Key::T],
+    [Key::N1, Key::N2, Key::N3, Key::N4, Key::N5],
+    [Key::N0, Key::N9, Key::N8, Key::N7, Key::N6],
+    [Key::P, Key::O, Key::I, Key::U, Key::Y],
+    [Key::Enter, Key::L, Key::K, Key::J, Key::H],
+    [Key::Space, Key::SymbolShift, Key::M, Key::N, Key::B],
+];
+
+const NAMES: [[&str; 5]; 8] = [
+    ["CAPS SHIFT", "Z", "X", "C", "V"],
+    ["A", "S", "D", "F", "G"],
+    ["Q", "W", "E", "R", "T"],
+    ["1", "2", "3", "4", "5"],
+    ["0", "9", "8", "7", "6"],
+    ["P", "O", "I", "U", "Y"],
+    ["ENTER", "L", "K", "J", "H"],
+    ["SPACE", "SYMBOL SHIFT", "M", "N", "B"],
+];
+
+impl Key {
+    /// Half-row and bit of the key in the matrix.
+    pub fn position(&self) -> (usize, usize) {
+        for (row, keys) in MATRIX.iter().enumerate() {
+            if let Some(bit) = keys.iter().position(|key| key == self) {
+                return (row, bit);
+            }
+        }
+        unreachable!()
+    }
+
+    /// The key labelled `name` ("A", "7", "ENTER", "CAPS SHIFT"...), ignoring
+    /// case.
+    pub fn from_name(name: &str) -> Result<Key, String> {
+        let name = name.trim().to_uppercase();
+        for (row, names) in NAMES.iter().enumerate() {
+            if let Some(bit) = names.iter().position(|key| *key == name) {
+                return Ok(MATRIX[row][bit]);
+            }
+        }
+        Err(format!("Unknown key {}", name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        let (row, bit) = self.position();
+        NAMES[row][bit]
+    }
+}
+
+/// Keys of the host keyboard a frontend forwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKey {
+    Char(char),
+    Enter,
+    Space,
+    Backspace,
+    Escape,
+    Left,
+    Down,
+    Up,
+    Right,
+    Shift,
+    Control,
+    Alt,
+}
+
+impl HostKey {
+    /// Spectrum keys a host key stands for. Editing keys are combinations
+    /// with CAPS SHIFT, punctuation with SYMBOL SHIFT.
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            HostKey::Enter => vec![Key::Enter],
+            HostKey::Space => vec![Key::Space],
+            HostKey::Backspace => vec![Key::CapsShift, Key::N0],
+            HostKey::Escape => vec![Key::CapsShift, Key::Space],
+            HostKey::Left => vec![Key::CapsShift, Key::N5],
+            HostKey::Down => vec![Key::CapsShift, Key::N6],
+            HostKey::Up => vec![Key::CapsShift, Key::N7],
+            HostKey::Right => vec![Key::CapsShift, Key::N8],
+            HostKey::Shift => vec![Key::CapsShift],
+            HostKey::Control | HostKey::Alt => vec![Key::SymbolShift],
+            HostKey::Char(c) => match c {
+                ',' => vec![Key::SymbolShift, Key::N],
+                '.' => vec![Key::SymbolShift, Key::M],
+                ';' => vec![Key::SymbolShift, Key::O],
+                '"' => vec![Key::SymbolShift, Key::P],
+                '-' => vec![Key::SymbolShift, Key::J],
+                '+' => vec![Key::SymbolShift, Key::K],
+                '=' => vec![Key::SymbolShift, Key::L],
+                '/' => vec![Key::SymbolShift, Key::V],
+                '*' => vec![Key::SymbolShift, Key::B],
+                c if c.is_ascii_alphanumeric() => {
+                    Key::from_name(&c.to_string()).map(|key| vec![key]).unwrap_or_default()
+                }
+                _ => vec![],
+            },
+        }
+    }
+}
+
+/// Whatever drives the EAR input, such as the tape deck. It is asked for the
+/// level every time port 0xFE is read, so it can catch up first.
+pub trait EarSource {
+    fn ear_level(&mut self) -> bool;
+}
+
+impl<T: EarSource> EarSource for Rc<RefCell<T>> {
+    fn ear_level(&mut self) -> bool { self.borrow_mut().ear_level() }
+}
+
+/// The keyboard matrix, read through port 0xFE. A key held by several host
+/// keys (CAPS SHIFT by Shift and Backspace) stays down until all of them are
+/// released. Bit 6 reads the EAR input, high with nothing connected.
+pub struct Keyboard {
+    pressed: [[u8; 5]; 8],
+    ear: Option<Box<dyn EarSource>>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            pressed: [[0; 5]; 8],
+            ear: None,
+        }
+    }
+
+    pub fn press(&mut self, key: Key) {
+        let (row, bit) = key.position();
+        self.pressed[row][bit] = self.pressed[row][bit].saturating_add(1);
+    }
+
+    pub fn release(&mut self, key: Key) {
+        let (row, bit) = key.position();
+        self.pressed[row][bit] = self.pressed[row][bit].saturating_sub(1);
+    }
+
+    pub fn release_all(&mut self) {
+        self.pressed = [[0; 5]; 8];
+    }
+
+    pub fn is_pressed(&self, key: Key) -> bool {
+        let (row, bit) = key.position();
+        self.pressed[row][bit] > 0
+    }
+
+    pub fn press_name(&mut self, name: &str) -> Result<(), String> {
+        self.press(Key::from_name(name)?);
+        Ok(())
+    }
+
+    pub fn release_name(&mut self, name: &str) -> Result<(), String> {
+        self.release(Key::from_name(name)?);
+        Ok(())
+    }
+
+    pub fn press_host(&mut self, host: HostKey) {
+        host.keys().into_iter().for_each(|key| self.press(key));
+    }
+
+    pub fn release_host(&mut self, host: HostKey) {
+        host.keys().into_iter().for_each(|key| self.release(key));
+    }
+
+    /// Connects the EAR input, replacing any previous source.
+    pub fn connect_ear(&mut self, source: Box<dyn EarSource>) {
+        self.ear = Some(source);
+    }
+
+    pub fn disconnect_ear(&mut self) {
+        self.ear = None;
+    }
+
+    /// Bits 0-4 of the half-rows selected by the low bits of `high`.
+    fn read_rows(&self, high: u8) -> u8 {
+        let mut value = 0x1F;
+        for (row, keys) in self.pressed.iter().enumerate() {
+            if high & (1 << row) == 0 {
+                for (bit, count) in keys.iter().enumerate() {
+                    if *count > 0 {
+                        value &= !(1 << bit);
+                    }
+                }
+            }
+        }
+        value
+    }
+}
+
+/// Any even port reads the keyboard: bits 0-4 are the keys of the half-rows
+/// selected by the high byte (active low), bit 6 is EAR.
+impl IoDevice for Keyboard {
+    fn get_port_mask(&self) -> u16 {
+        0x0001
+    }
+
+    fn get_port_match(&self) -> u16 {
+        0x0000
+    }
+
+    fn read_port(&mut self, port: u16) -> u8 {
+        let level = self.ear.as_mut().is_none_or(|source| source.ear_level());
+        let ear = if level { 0x40 } else { 0x00 };
+        0xA0 | ear | self.read_rows((port >> 8) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test_keyboard {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::bus::{Bus, IoDevice};
+
+    use super::{EarSource, HostKey, Key, Keyboard};
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Key::from_name("caps shift"), Ok(Key::CapsShift));
+        assert_eq!(Key::from_name("7"), Ok(Key::N7));
+        assert_eq!(Key::from_name("b"), Ok(Key::B));
+        assert!(Key::from_name("F1").is_err());
+        assert_eq!(Key::SymbolShift.name(), "SYMBOL SHIFT");
+        assert_eq!(Key::Y.position(), (5, 4));
+    }
+
+    #[test]
+    fn test_matrix() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.read_port(0x00FE), 0xFF);
+
+        keyboard.press(Key::A);
+        keyboard.press_name("SPACE").unwrap();
+        assert_eq!(keyboard.read_port(0xFDFE), 0b11111110);
+        assert_eq!(keyboard.read_port(0x7FFE), 0b11111110);
+        assert_eq!(keyboard.read_port(0xFEFE), 0xFF);
+        assert_eq!(keyboard.read_port(0x00FE), 0b11111110);
+
+        keyboard.press(Key::G);
+        assert_eq!(keyboard.read_port(0xFDFE), 0b11101110);
+        keyboard.release(Key::A);
+        keyboard.release_name("space").unwrap();
+        assert_eq!(keyboard.read_port(0x7DFE), 0b11101111);
+
+        keyboard.connect_ear(Box::new(Low));
+        assert_eq!(keyboard.read_port(0xFFFE), 0b10111111);
+        keyboard.disconnect_ear();
+        assert_eq!(keyboard.read_port(0xFFFE), 0xFF);
+    }
+
+    struct Low;
+    impl EarSource for Low {
+        fn ear_level(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_host_keys() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press_host(HostKey::Shift);
+        keyboard.press_host(HostKey::Backspace);
+        assert!(keyboard.is_pressed(Key::CapsShift));
+        assert!(keyboard.is_pressed(Key::N0));
+
+        keyboard.release_host(HostKey::Backspace);
+        assert!(keyboard.is_pressed(Key::CapsShift));
+        assert!(!keyboard.is_pressed(Key::N0));
+        keyboard.release_host(HostKey::Shift);
+        assert!(!keyboard.is_pressed(Key::CapsShift));
+
+        assert_eq!(HostKey::Up.keys(), vec![Key::CapsShift, Key::N7]);
+        assert_eq!(HostKey::Char('q').keys(), vec![Key::Q]);
+        assert_eq!(HostKey::Char('.').keys(), vec![Key::SymbolShift, Key::M]);
+        assert_eq!(HostKey::Char('~').keys(), vec![]);
+    }
+
+    #[test]
+    fn test_bus() {
+        let keyboard = Rc::new(RefCell::new(Keyboard::new()));
+        let mut bus = Bus::new();
+        bus.add_io_device(Box::new(Rc::clone(&keyboard)));
+
+        keyboard.borrow_mut().press(Key::Enter);
+        assert_eq!(bus.read_port(0xBFFE), 0b11111110);
+        assert_eq!(bus.read_port(0xBFFF), 0xFF);
+    }
+}
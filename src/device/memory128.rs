@@ -0,0 +1,283 @@
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+use crate::{bus::{BusDevice, IoDevice}, clock::Clock, screen::VideoMemory};
+
+const BANK_SIZE: usize = 0x4000;
+/// Bitmap and attributes at the start of the screen bank.
+const SCREEN_SIZE: usize = 0x1B00;
+
+const PAGING_RAM: u8 = 0b00000111;
+const PAGING_SCREEN: u8 = 0b00001000;
+const PAGING_ROM: u8 = 0b00010000;
+const PAGING_LOCK: u8 = 0b00100000;
+
+/// ZX Spectrum 128K memory: two 16K ROMs and eight 16K RAM banks covering the
+/// whole address space, paged through port 0x7FFD.
+///
+/// 0x0000-0x3FFF holds the selected ROM, 0x4000 bank 5, 0x8000 bank 2 and
+/// 0xC000 the bank selected by bits 0-2 of the paging register. Bit 3 selects
+/// the shadow screen in bank 7, bit 4 the ROM and bit 5 locks paging until
+/// reset. Add it to the bus as an `Rc<RefCell<Memory128>>`, both as a memory
+/// device and as an I/O device.
+pub struct Memory128 {
+    rom: [Vec<u8>; 2],
+    ram: [Vec<u8>; 8],
+    paging: u8,
+    clock: Rc<RefCell<Clock>>
+}
+
+impl Memory128 {
+    pub fn from_bytes(rom0: &[u8], rom1: &[u8], clock: Rc<RefCell<Clock>>) -> Result<Self, String> {
+        for (i, image) in [rom0, rom1].iter().enumerate() {
+            if image.len() != BANK_SIZE {
+                return Err(format!("ROM {} image is {} bytes, expected {}", i, image.len(), BANK_SIZE));
+            }
+        }
+
+        Ok(Memory128 {
+            rom: [rom0.to_vec(), rom1.to_vec()],
+            ram: std::array::from_fn(|_| vec![0x00; BANK_SIZE]),
+            paging: 0,
+            clock,
+        })
+    }
+
+    pub fn from_files<P: AsRef<Path>>(rom0: P, rom1: P, clock: Rc<RefCell<Clock>>) -> Result<Self, String> {
+        let read = |path: &Path| fs::read(path)
+            .map_err(|error| format!("Cannot read ROM image {}: {}", path.display(), error));
+        Self::from_bytes(&read(rom0.as_ref())?, &read(rom1.as_ref())?, clock)
+    }
+
+    /// Last value written to port 0x7FFD.
+    pub fn paging(&self) -> u8 {
+        self.paging
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.paging & PAGING_LOCK != 0
+    }
+
+    /// RAM bank the ULA displays: 5, or 7 with the shadow screen selected.
+    pub fn screen_bank(&self) -> usize {
+        if self.paging & PAGING_SCREEN != 0 { 7 } else { 5 }
+    }
+
+    pub fn bank(&self, bank: usize) -> &[u8] {
+        &self.ram[bank]
+    }
+
+    /// Pages in ROM 0 and bank 0 and unlocks paging, as the reset line does.
+    pub fn reset(&mut self) {
+        self.paging = 0;
+    }
+
+    fn slot(&self, address: u16) -> (Option<usize>, usize) {
+        let offset = address as usize & (BANK_SIZE - 1);
+        let bank = match address >> 14 {
+            0 => None,
+            1 => Some(5),
+            2 => Some(2),
+            _ => Some((self.paging & PAGING_RAM) as usize),
+        };
+        (bank, offset)
+    }
+
+    fn rom_index(&self) -> usize {
+        if self.paging & PAGING_ROM != 0 { 1 } else { 0 }
+    }
+}
+
+impl BusDevice for Memory128 {
+    fn get_base_address(&self) -> u16 {
+        0x0000
+    }
+
+    fn get_size(&self) -> u32 {
+        0x10000
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.clock.borrow_mut().add(3);
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.clock.borrow_mut().add(3);
+        if let (Some(bank), offset) = self.slot(address) {
+            self.ram[bank][offset] = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match self.slot(address) {
+            (Some(bank), offset) => self.ram[bank][offset],
+            (None, offset) => self.rom[self.rom_index()][offset],
+        }
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        let rom = self.rom_index();
+        match self.slot(address) {
+            (Some(bank), offset) => self.ram[bank][offset] = value,
+            (None, offset) => self.rom[rom][offset] = value,
+        }
+    }
+
+    fn write_vec(&mut self, address: u16, data: Vec<u8>) {
+        for (i, value) in data.iter().enumerate() {
+            self.poke(address.wrapping_add(i as u16), *value)
+        }
+    }
+
+    fn read_word(&self, address: u16) -> u16 {
+        let data = (self.read(address.wrapping_add(1)) as u16) << 8;
+        data + self.read(address) as u16
+    }
+
+    fn is_writable(&self, address: u16) -> bool {
+        self.slot(address).0.is_some()
+    }
+
+    /// Odd banks are contended, bank 5 at 0x4000 included.
+    fn is_contended(&self, address: u16) -> bool {
+        matches!(self.slot(address), (Some(bank), _) if bank & 1 == 1)
+    }
+}
+
+/// Port 0x7FFD is decoded by A15 and A1 low.
+impl IoDevice for Memory128 {
+    fn get_port_mask(&self) -> u16 {
+        0x8002
+    }
+
+    fn get_port_match(&self) -> u16 {
+        0x0000
+    }
+
+    fn write_port(&mut self, _port: u16, value: u8) {
+        if !self.is_locked() {
+            self.paging = value;
+        }
+    }
+}
+
+/// The ULA reads bank 5 or 7 directly, whatever is paged in.
+impl VideoMemory for Memory128 {
+    fn peek_screen(&self, offset: u16) -> u8 {
+        self.ram[self.screen_bank()][offset as usize]
+    }
+
+    fn is_screen_address(&self, address: u16) -> bool {
+        matches!(self.slot(address), (Some(bank), offset) if bank == self.screen_bank() && offset < SCREEN_SIZE)
+    }
+
+    fn pages_screen(&self, port: u16) -> bool {
+        port & self.get_port_mask() == self.get_port_match() && !self.is_locked()
+    }
+}
+
+#[cfg(test)]
+mod test_memory128 {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{bus::Bus, clock::Clock, device::memory128::Memory128, screen::VideoMemory};
+
+    fn init() -> (Bus, Rc<RefCell<Memory128>>) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let memory = Rc::new(RefCell::new(
+            Memory128::from_bytes(&[0x00; 0x4000], &[0x01; 0x4000], clock).unwrap()
+        ));
+        let mut bus = Bus::new();
+        bus.add_device(Box::new(Rc::clone(&memory))).unwrap();
+        bus.add_io_device(Box::new(Rc::clone(&memory)));
+        (bus, memory)
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        assert!(Memory128::from_bytes(&[0x00; 0x4000], &[0x00; 0x100], clock).is_err());
+    }
+
+    #[test]
+    fn test_rom_paging() {
+        let (mut bus, _) = init();
+        assert_eq!(bus.read(0x0000), 0x00);
+        bus.write(0x0000, 0xAA);
+        assert_eq!(bus.read(0x0000), 0x00);
+
+        bus.write_port(0x7FFD, 0b00010000);
+        assert_eq!(bus.read(0x3FFF), 0x01);
+    }
+
+    #[test]
+    fn test_ram_paging() {
+        let (mut bus, memory) = init();
+        bus.write(0x4000, 0x55);
+        bus.write(0x8000, 0x22);
+        bus.write(0xC000, 0x00);
+
+        bus.write_port(0x7FFD, 5);
+        assert_eq!(bus.read(0xC000), 0x55);
+        bus.write_port(0x7FFD, 2);
+        assert_eq!(bus.read(0xC000), 0x22);
+
+        bus.write_port(0x7FFD, 7);
+        bus.write(0xFFFF, 0x77);
+        assert_eq!(memory.borrow().bank(7)[0x3FFF], 0x77);
+        assert_eq!(memory.borrow().bank(0)[0x3FFF], 0x00);
+    }
+
+    #[test]
+    fn test_screen_and_lock() {
+        let (mut bus, memory) = init();
+        assert_eq!(memory.borrow().screen_bank(), 5);
+
+        bus.write_port(0x7FFD, 0b00101011);
+        assert_eq!(memory.borrow().screen_bank(), 7);
+        assert!(memory.borrow().is_locked());
+
+        bus.write_port(0x7FFD, 0);
+        assert_eq!(memory.borrow().paging(), 0b00101011);
+
+        memory.borrow_mut().reset();
+        bus.write_port(0x7FFD, 1);
+        assert_eq!(memory.borrow().paging(), 1);
+    }
+
+    #[test]
+    fn test_port_decoding() {
+        let (mut bus, memory) = init();
+        bus.write_port(0xFFFD, 1);
+        bus.write_port(0x7FFF, 1);
+        assert_eq!(memory.borrow().paging(), 0);
+        bus.write_port(0x1FFD, 3);
+        assert_eq!(memory.borrow().paging(), 3);
+    }
+
+    #[test]
+    fn test_video_memory() {
+        let (mut bus, memory) = init();
+        bus.write(0x4000, 0x55);
+        bus.write_port(0x7FFD, 7);
+        bus.write(0xC000, 0x77);
+        assert_eq!(memory.borrow().peek_screen(0x0000), 0x55);
+
+        bus.write_port(0x7FFD, 0b00001000);
+        assert_eq!(memory.borrow().peek_screen(0x0000), 0x77);
+    }
+
+    #[test]
+    fn test_contended_banks() {
+        let (mut bus, _) = init();
+        assert!(!bus.is_contended(0x0000));
+        assert!(bus.is_contended(0x4000));
+        assert!(!bus.is_contended(0x8000));
+        assert!(!bus.is_contended(0xC000));
+
+        bus.write_port(0x7FFD, 3);
+        assert!(bus.is_contended(0xC000));
+        bus.write_port(0x7FFD, 4);
+        assert!(!bus.is_contended(0xFFFF));
+    }
+}
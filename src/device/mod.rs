@@ -1 +1,6 @@
+pub mod ay;
+pub mod beeper;
+pub mod keyboard;
+pub mod memory128;
 pub mod ram;
+pub mod rom;
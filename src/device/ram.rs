@@ -1,20 +1,20 @@
-use std::{cell::RefCell, iter::repeat, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 use crate::{bus::BusDevice, clock::Clock};
 
 pub struct Ram {
     base_address: u16,
-    size: u16,
+    size: u32,
     data: Vec<u8>,
     clock: Rc<RefCell<Clock>>
 }
 
 impl Ram {
-    pub fn new(base_address: u16, size: u16, clock: Rc<RefCell<Clock>>) -> Self {
+    pub fn new(base_address: u16, size: u32, clock: Rc<RefCell<Clock>>) -> Self {
         Ram {
             base_address,
             size,
-            data: Vec::from_iter(repeat(0x00).take(size as usize)),
+            data: vec![0x00; size as usize],
             clock,
         }
     }
@@ -25,7 +25,7 @@ impl BusDevice for Ram {
         self.base_address
     }
 
-    fn get_size(&self) -> u16 {
+    fn get_size(&self) -> u32 {
         self.size
     }
 
@@ -54,8 +54,8 @@ impl BusDevice for Ram {
     }
 
     fn read_word(&self, address: u16) -> u16 {
-        let data = (self.read(address - self.base_address + 1) as u16) << 8;
-        data + self.read(address - self.base_address) as u16
+        let data = (self.read(address.wrapping_add(1)) as u16) << 8;
+        data + self.read(address) as u16
     }
 }
 
@@ -110,4 +110,13 @@ mod test_ram {
         assert_eq!(ram.read_word(0x0000), 0x1234);
         assert_eq!(clock.borrow().read(), 6);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_read_word_with_base() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut ram = Ram::new(0xC000, 0x4000, clock);
+
+        ram.write_vec(0xFFFE, vec![0x34, 0x12]);
+        assert_eq!(ram.read_word(0xFFFE), 0x1234);
+    }
+}
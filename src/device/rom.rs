@@ -0,0 +1,153 @@
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+use crate::{bus::BusDevice, clock::Clock};
+
+/// Read-only memory. Writes from the CPU are ignored but still take the
+/// memory cycle; `poke` patches the image for debugging.
+pub struct Rom {
+    base_address: u16,
+    size: u32,
+    data: Vec<u8>,
+    clock: Rc<RefCell<Clock>>
+}
+
+impl Rom {
+    pub fn from_bytes(base_address: u16, size: u32, image: &[u8], clock: Rc<RefCell<Clock>>) -> Result<Self, String> {
+        if image.len() != size as usize {
+            return Err(format!("ROM image is {} bytes, expected {}", image.len(), size));
+        }
+
+        Ok(Rom {
+            base_address,
+            size,
+            data: image.to_vec(),
+            clock,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(base_address: u16, size: u32, path: P, clock: Rc<RefCell<Clock>>) -> Result<Self, String> {
+        let image = fs::read(path.as_ref())
+            .map_err(|error| format!("Cannot read ROM image {}: {}", path.as_ref().display(), error))?;
+        Self::from_bytes(base_address, size, &image, clock)
+    }
+
+    /// Offset of `address` in the image, if the ROM covers it.
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.wrapping_sub(self.base_address) as u32;
+        (offset < self.size).then_some(offset as usize)
+    }
+}
+
+impl BusDevice for Rom {
+    fn get_base_address(&self) -> u16 {
+        self.base_address
+    }
+
+    fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.clock.borrow_mut().add(3);
+        self.peek(address)
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) {
+        self.clock.borrow_mut().add(3);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.data[(address - self.base_address) as usize]
+    }
+
+    fn is_writable(&self, _address: u16) -> bool {
+        false
+    }
+
+    /// Bytes outside the image are dropped.
+    fn poke(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.offset(address) {
+            self.data[offset] = value;
+        }
+    }
+
+    fn write_vec(&mut self, address: u16, data: Vec<u8>) {
+        for (i, value) in data.iter().enumerate() {
+            self.poke(address.wrapping_add(i as u16), *value)
+        }
+    }
+
+    fn read_word(&self, address: u16) -> u16 {
+        let data = (self.read(address.wrapping_add(1)) as u16) << 8;
+        data + self.read(address) as u16
+    }
+}
+
+#[cfg(test)]
+mod test_rom {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{bus::BusDevice, clock::Clock, device::rom::Rom};
+
+    fn init() -> (Rom, Rc<RefCell<Clock>>) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        (Rom::from_bytes(0x0000, 0x04, &[0xF3, 0xAF, 0x11, 0xFF], clock.clone()).unwrap(), clock)
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        assert!(Rom::from_bytes(0x0000, 0x4000, &[0x00; 0x100], clock.clone()).is_err());
+        assert!(Rom::from_file(0x0000, 0x4000, "does/not/exist.rom", clock).is_err());
+    }
+
+    #[test]
+    fn test_read_write() {
+        let (mut rom, clock) = init();
+
+        rom.write(0x0000, 0x00); // 3 tics
+        assert_eq!(rom.read(0x0000), 0xF3); // 3 + 3 tics
+        assert_eq!(rom.read_word(0x0002), 0xFF11); // 3 + 3 + 6 tics
+        assert_eq!(clock.borrow().read(), 12);
+    }
+
+    #[test]
+    fn test_poke() {
+        let (mut rom, clock) = init();
+
+        rom.poke(0x0001, 0x00);
+        assert_eq!(rom.peek(0x0001), 0x00);
+        assert_eq!(clock.borrow().read(), 0);
+    }
+
+    #[test]
+    fn test_write_vec_past_end() {
+        let (mut rom, _) = init();
+        rom.write_vec(0x0002, vec![0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(rom.peek(0x0001), 0xAF);
+        assert_eq!(rom.peek(0x0002), 0x01);
+        assert_eq!(rom.peek(0x0003), 0x02);
+
+        // past 0xFFFF the addresses wrap, outside the image
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut rom = Rom::from_bytes(0xFFFC, 0x04, &[0x00; 4], clock).unwrap();
+        rom.write_vec(0xFFFE, vec![0x01, 0x02, 0x03]);
+        assert_eq!(rom.peek(0xFFFE), 0x01);
+        assert_eq!(rom.peek(0xFFFF), 0x02);
+        assert_eq!(rom.peek(0xFFFC), 0x00);
+    }
+
+    #[test]
+    fn test_from_file() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        // unique per process, so concurrent test runs don't share the file
+        let path = std::env::temp_dir().join(format!("semr_test_rom_{}.bin", std::process::id()));
+        std::fs::write(&path, [0x01, 0x02]).unwrap();
+
+        let rom = Rom::from_file(0x0000, 0x02, &path, clock);
+        std::fs::remove_file(&path).unwrap();
+        let rom = rom.unwrap();
+        assert_eq!(rom.peek(0x0000), 0x01);
+        assert_eq!(rom.peek(0x0001), 0x02);
+    }
+}
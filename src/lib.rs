@@ -1,5 +1,9 @@
+pub mod audio;
 pub mod clock;
 pub mod bus;
+pub mod contention;
 pub mod device;
 pub mod cpu;
-pub mod screen;
\ No newline at end of file
+pub mod model;
+pub mod screen;
+pub mod tape;
@@ -1,19 +1,79 @@
 use std::{cell::RefCell, rc::Rc};
 
-use semr::{bus::Bus, clock::Clock, cpu::{Cpu, RefBus, RefClock}, device::ram::Ram, screen::Screen};
+use semr::{audio::AudioBuffer, bus::{Bus, BusDevice}, clock::Clock, contention::Contention, cpu::{Cpu, RefBus, RefClock, StopReason}, device::{beeper::Beeper, keyboard::Keyboard, ram::Ram, rom::Rom}, model::Model, screen::Screen, tape::{deck::TapeDeck, tap::Tap}};
 
 extern crate semr;
 
 fn main() {
+    let model = Model::Spectrum48K;
     let clock: RefClock = Rc::new(RefCell::new(Clock::new()));
     let bus: RefBus = Rc::new(RefCell::new(Bus::new()));
 
-    bus.borrow_mut().add_device(Box::new(Ram::new(0x0000, 0x100, Rc::clone(&clock)))).unwrap();
+    // With a ROM image as argument boot it at 0x0000 like a 16K Spectrum ROM,
+    // otherwise start with a zeroed RAM. A TAP file may follow for fast
+    // loading, or a TZX file to play in real time.
+    let low: Box<dyn BusDevice> = match std::env::args().nth(1) {
+        Some(path) => match Rom::from_file(0x0000, 0x4000, &path, Rc::clone(&clock)) {
+            Ok(rom) => Box::new(rom),
+            Err(error) => {
+                eprintln!("{}", error);
+                return;
+            }
+        },
+        None => Box::new(Ram::new(0x0000, 0x4000, Rc::clone(&clock))),
+    };
+    let ram = Rc::new(RefCell::new(Ram::new(0x4000, 0xC000, Rc::clone(&clock))));
+    let screen = Rc::new(RefCell::new(Screen::new(ram.clone(), Rc::clone(&clock), model)));
+
+    bus.borrow_mut().add_device(low).unwrap();
+    bus.borrow_mut().add_device(Box::new(ram)).unwrap();
+    bus.borrow_mut().add_io_device(Box::new(Rc::clone(&screen)));
+    bus.borrow_mut().add_write_observer(Box::new(Rc::clone(&screen)));
+    let keyboard = Rc::new(RefCell::new(Keyboard::new()));
+    bus.borrow_mut().add_io_device(Box::new(Rc::clone(&keyboard)));
+    let beeper = Rc::new(RefCell::new(Beeper::new(model, Rc::clone(&clock), 44100)));
+    bus.borrow_mut().add_io_device(Box::new(Rc::clone(&beeper)));
+    let mut audio = AudioBuffer::new(44100);
+    let deck = Rc::new(RefCell::new(TapeDeck::new(model, Rc::clone(&clock))));
+    keyboard.borrow_mut().connect_ear(Box::new(Rc::clone(&deck)));
+    bus.borrow_mut().set_contention(Contention::new(model, Rc::clone(&clock)));
 
     let mut cpu = Cpu::new(Rc::clone(&bus), Rc::clone(&clock));
-    let screen = Screen::new(Rc::clone(&bus), Rc::clone(&clock));
+    cpu.set_model(model);
 
     cpu.reset();
-    cpu.execute();
-    screen.peek_bus(0x0000);
+    if let Some(path) = std::env::args().nth(2) {
+        let loaded = if path.to_lowercase().ends_with(".tzx") {
+            let mut deck = deck.borrow_mut();
+            deck.insert_file(&path).map(|_| deck.play())
+        } else {
+            Tap::from_file(&path).map(|tap| cpu.insert_tape(tap))
+        };
+        if let Err(error) = loaded {
+            eprintln!("{}", error);
+            return;
+        }
+    }
+
+    // One emulated second.
+    let mut instructions = 0;
+    let mut samples = 0;
+    for _ in 0..50 {
+        audio.clear();
+        let summary = cpu.run_frame();
+        screen.borrow_mut().update();
+        beeper.borrow_mut().render(&mut audio);
+        deck.borrow_mut().update();
+        instructions += summary.instructions;
+        samples += audio.len();
+        if let StopReason::Error(error) = summary.reason {
+            eprintln!("{}", error);
+            break;
+        }
+    }
+    println!("frames: {}", screen.borrow().frames());
+    println!("instructions: {}", instructions);
+    println!("samples: {}", samples);
+    println!("tape block: {}/{}", deck.borrow().position(), deck.borrow().block_count());
+    println!("clock: {}", clock.borrow().read());
 }
@@ -0,0 +1,70 @@
+/// Machine timings the ULA and the run loop depend on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Spectrum48K,
+    Spectrum128K,
+}
+
+impl Model {
+    /// CPU clock frequency in Hz.
+    pub fn cpu_hz(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 3_500_000,
+            Model::Spectrum128K => 3_546_900,
+        }
+    }
+
+    pub fn t_states_per_line(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 224,
+            Model::Spectrum128K => 228,
+        }
+    }
+
+    pub fn lines(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 312,
+            Model::Spectrum128K => 311,
+        }
+    }
+
+    pub fn t_states_per_frame(&self) -> u32 {
+        self.t_states_per_line() * self.lines()
+    }
+
+    /// Scanline showing the first row of the 256x192 paper area.
+    pub fn first_paper_line(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 64,
+            Model::Spectrum128K => 63,
+        }
+    }
+
+    /// T-states the ULA holds INT active at the start of every frame.
+    pub fn interrupt_length(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 32,
+            Model::Spectrum128K => 36,
+        }
+    }
+
+    /// Frame T-state of the first access the ULA contends, a little before
+    /// the first paper pixel is displayed.
+    pub fn first_contended_t_state(&self) -> u32 {
+        match self {
+            Model::Spectrum48K => 14335,
+            Model::Spectrum128K => 14361,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_model {
+    use super::Model;
+
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(Model::Spectrum48K.t_states_per_frame(), 69888);
+        assert_eq!(Model::Spectrum128K.t_states_per_frame(), 70908);
+    }
+}
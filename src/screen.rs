@@ -1,20 +1,472 @@
-use crate::cpu::{RefBus, RefClock};
+use std::{cell::RefCell, rc::Rc};
 
+use crate::{bus::{Bus, BusDevice, IoDevice, WriteObserver}, cpu::RefClock, device::ram::Ram, model::Model};
+
+pub const PAPER_WIDTH: usize = 256;
+pub const PAPER_HEIGHT: usize = 192;
+pub const BORDER_LEFT: usize = 48;
+pub const BORDER_RIGHT: usize = 48;
+pub const BORDER_TOP: usize = 48;
+pub const BORDER_BOTTOM: usize = 56;
+pub const WIDTH: usize = BORDER_LEFT + PAPER_WIDTH + BORDER_RIGHT;
+pub const HEIGHT: usize = BORDER_TOP + PAPER_HEIGHT + BORDER_BOTTOM;
+
+const ATTRIBUTES: u16 = 0x1800;
+const FLASH_FRAMES: u32 = 16;
+
+/// The ULA draws 8 pixels every 4 T-states: rendering goes a cell at a time.
+const CELL_WIDTH: usize = 8;
+const CELL_T_STATES: u32 = 4;
+const CELLS_PER_ROW: usize = WIDTH / CELL_WIDTH;
+const BORDER_LEFT_CELLS: usize = BORDER_LEFT / CELL_WIDTH;
+const PAPER_CELLS: usize = PAPER_WIDTH / CELL_WIDTH;
+
+/// Spectrum palette as 0xRRGGBBAA, normal colours followed by their bright
+/// versions.
+const PALETTE: [u32; 16] = [
+    0x000000FF, 0x0000D7FF, 0xD70000FF, 0xD700D7FF, 0x00D700FF, 0x00D7D7FF, 0xD7D700FF, 0xD7D7D7FF,
+    0x000000FF, 0x0000FFFF, 0xFF0000FF, 0xFF00FFFF, 0x00FF00FF, 0x00FFFFFF, 0xFFFF00FF, 0xFFFFFFFF,
+];
+
+/// Source of the 6912 bytes the ULA displays: the bitmap at offset 0 and the
+/// attributes at 0x1800.
+pub trait VideoMemory {
+    fn peek_screen(&self, offset: u16) -> u8;
+    /// Whether a CPU write to `address` lands in the displayed memory.
+    fn is_screen_address(&self, address: u16) -> bool { (0x4000..0x5B00).contains(&address) }
+    /// Whether a write to `port` may switch the displayed memory.
+    fn pages_screen(&self, _port: u16) -> bool { false }
+}
+
+/// A 48K machine displays whatever is mapped at 0x4000.
+impl VideoMemory for Bus {
+    fn peek_screen(&self, offset: u16) -> u8 {
+        self.peek(0x4000 + offset)
+    }
+}
+
+/// The RAM holding 0x4000-0x5AFF. Sharing it with the screen, rather than the
+/// whole bus, lets the screen catch up from inside a bus access.
+impl VideoMemory for Ram {
+    fn peek_screen(&self, offset: u16) -> u8 {
+        self.peek(0x4000 + offset)
+    }
+}
+
+/// The ULA: renders screen memory and the border to a `WIDTH` x `HEIGHT`
+/// RGBA framebuffer, following the beam.
+///
+/// `update` draws every cell the beam has reached according to the clock, so
+/// border changes and attribute writes land on the scanline where the CPU
+/// made them. The screen catches up by itself when added to the bus as an
+/// `Rc<RefCell<Screen>>` I/O device (border writes on port 0xFE) and write
+/// observer (screen memory writes).
 pub struct Screen {
-    bus: RefBus,
-    clock: RefClock
+    memory: Rc<RefCell<dyn VideoMemory>>,
+    clock: RefClock,
+    model: Model,
+    border: u8,
+    frames: u32,
+    /// Clock frame the beam is drawing and next cell to draw in it.
+    frame: u64,
+    cell: usize,
+    last_clock: u64,
+    framebuffer: Vec<u32>,
 }
 
 impl Screen {
-    pub fn new(bus: RefBus, clock: RefClock) -> Self {
+    /// Frames the clock for `model`, so the beam follows its timings.
+    pub fn new(memory: Rc<RefCell<dyn VideoMemory>>, clock: RefClock, model: Model) -> Self {
+        clock.borrow_mut().set_frame_length(model.t_states_per_frame());
         Self {
-            bus,
-            clock
+            memory,
+            clock,
+            model,
+            border: 0,
+            frames: 0,
+            frame: 0,
+            cell: 0,
+            last_clock: 0,
+            framebuffer: vec![PALETTE[0]; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn border(&self) -> u8 {
+        self.border
+    }
+
+    /// Changes the border colour from the current beam position on.
+    pub fn set_border(&mut self, colour: u8) {
+        self.update();
+        self.border = colour & 0x07;
+    }
+
+    /// Frames completed so far.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Draws up to the beam position given by the clock, completing any frame
+    /// the clock has left behind.
+    pub fn update(&mut self) {
+        let (now, frame, position) = {
+            let clock = self.clock.borrow();
+            (clock.read(), clock.frames(), clock.frame_tics())
+        };
+        if now < self.last_clock {
+            // The clock was reset: restart the frame.
+            self.frame = 0;
+            self.cell = 0;
+        }
+        self.last_clock = now;
+
+        if frame > self.frame {
+            self.render_frame();
+            // Skipped frames are never seen: resync on the current one.
+            self.frame = frame;
+        }
+
+        if frame == self.frame {
+            self.render_until(position);
         }
     }
 
-    pub fn peek_bus(&self, address: u16) {
-        println!("value: {}", self.bus.borrow().peek(address));
-        println!("clock: {}", self.clock.borrow().read());
+    /// Completes the frame being drawn and moves the beam to the next one.
+    /// FLASH swaps ink and paper every 16 frames.
+    pub fn render_frame(&mut self) {
+        while self.cell < CELLS_PER_ROW * HEIGHT {
+            self.render_cell(self.cell);
+            self.cell += 1;
+        }
+        self.cell = 0;
+        self.frame = self.frame.wrapping_add(1);
+        self.frames = self.frames.wrapping_add(1);
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    /// Draws every cell whose T-state within the frame is before `t_state`.
+    fn render_until(&mut self, t_state: u32) {
+        while self.cell < CELLS_PER_ROW * HEIGHT && self.cell_t_state(self.cell) < t_state {
+            self.render_cell(self.cell);
+            self.cell += 1;
+        }
+    }
+
+    /// T-state at which the beam reaches a cell. The left border of a line is
+    /// drawn right before its paper, which starts at a multiple of the line
+    /// length.
+    fn cell_t_state(&self, cell: usize) -> u32 {
+        let row = (cell / CELLS_PER_ROW) as u32;
+        let column = (cell % CELLS_PER_ROW) as u32;
+        let line = self.model.first_paper_line() - BORDER_TOP as u32 + row;
+        line * self.model.t_states_per_line() - BORDER_LEFT_CELLS as u32 * CELL_T_STATES + column * CELL_T_STATES
+    }
+
+    fn render_cell(&mut self, cell: usize) {
+        let row = cell / CELLS_PER_ROW;
+        let column = cell % CELLS_PER_ROW;
+        let start = row * WIDTH + column * CELL_WIDTH;
+        let pixels = &mut self.framebuffer[start..start + CELL_WIDTH];
+
+        let paper_row = (BORDER_TOP..BORDER_TOP + PAPER_HEIGHT).contains(&row);
+        let paper_column = (BORDER_LEFT_CELLS..BORDER_LEFT_CELLS + PAPER_CELLS).contains(&column);
+        if !(paper_row && paper_column) {
+            pixels.fill(PALETTE[self.border as usize]);
+            return;
+        }
+
+        let y = row - BORDER_TOP;
+        let column = column - BORDER_LEFT_CELLS;
+        let flash = (self.frames / FLASH_FRAMES) & 1 == 1;
+        let memory = self.memory.borrow();
+        let bitmap = memory.peek_screen(pixel_offset(y, column));
+        let attribute = memory.peek_screen(ATTRIBUTES + ((y / 8) * 32 + column) as u16);
+        let (ink, paper) = colours(attribute, flash);
+
+        for (bit, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = if bitmap & (0x80 >> bit) != 0 { ink } else { paper };
+        }
+    }
+}
+
+/// The ULA answers every even port; OUT to 0xFE sets the border in bits 0-2.
+impl IoDevice for Screen {
+    fn get_port_mask(&self) -> u16 {
+        0x0001
+    }
+
+    fn get_port_match(&self) -> u16 {
+        0x0000
+    }
+
+    fn write_port(&mut self, _port: u16, value: u8) {
+        self.set_border(value);
+    }
+}
+
+/// Catches up before writes to the displayed memory, wherever it is paged,
+/// and before paging changes that switch it.
+impl WriteObserver for Screen {
+    fn before_write(&mut self, address: u16) {
+        let displayed = self.memory.borrow().is_screen_address(address);
+        if displayed {
+            self.update();
+        }
+    }
+
+    fn before_port_write(&mut self, port: u16) {
+        let paging = self.memory.borrow().pages_screen(port);
+        if paging {
+            self.update();
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Bitmap offset of a character cell: the y coordinate is stored as
+/// third (bits 7-6), pixel row (bits 2-0) and character row (bits 5-3).
+fn pixel_offset(y: usize, column: usize) -> u16 {
+    (((y & 0xC0) << 5) | ((y & 0x07) << 8) | ((y & 0x38) << 2) | column) as u16
+}
+
+fn colours(attribute: u8, flash: bool) -> (u32, u32) {
+    let bright = ((attribute & 0x40) >> 3) as usize;
+    let ink = PALETTE[(attribute & 0x07) as usize | bright];
+    let paper = PALETTE[((attribute >> 3) & 0x07) as usize | bright];
+
+    if flash && attribute & 0x80 != 0 {
+        (paper, ink)
+    } else {
+        (ink, paper)
+    }
+}
+
+#[cfg(test)]
+mod test_screen {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{bus::Bus, clock::Clock, cpu::{RefBus, RefClock}, device::{memory128::Memory128, ram::Ram}, model::Model};
+
+    use super::{pixel_offset, Screen, BORDER_LEFT, BORDER_TOP, HEIGHT, PALETTE, WIDTH};
+
+    fn init() -> (RefBus, RefClock, Rc<RefCell<Screen>>) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let ram = Rc::new(RefCell::new(Ram::new(0x4000, 0x1B00, Rc::clone(&clock))));
+        let screen = Rc::new(RefCell::new(Screen::new(ram.clone(), Rc::clone(&clock), Model::Spectrum48K)));
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        bus.borrow_mut().add_device(Box::new(ram)).unwrap();
+        bus.borrow_mut().add_io_device(Box::new(Rc::clone(&screen)));
+        bus.borrow_mut().add_write_observer(Box::new(Rc::clone(&screen)));
+        (bus, clock, screen)
+    }
+
+    fn pixel(screen: &Screen, x: usize, y: usize) -> u32 {
+        screen.framebuffer()[(BORDER_TOP + y) * WIDTH + BORDER_LEFT + x]
+    }
+
+    /// Moves the clock to a T-state of the first frame.
+    fn at(clock: &RefClock, t_state: u32) {
+        let now = clock.borrow().read() as u32;
+        clock.borrow_mut().add(t_state - now);
+    }
+
+    #[test]
+    fn test_pixel_offset() {
+        assert_eq!(pixel_offset(0, 0), 0x0000);
+        assert_eq!(pixel_offset(1, 0), 0x0100);
+        assert_eq!(pixel_offset(8, 0), 0x0020);
+        assert_eq!(pixel_offset(64, 1), 0x0801);
+        assert_eq!(pixel_offset(191, 31), 0x17FF);
+    }
+
+    #[test]
+    fn test_cell_timing() {
+        let (_, _, screen) = init();
+        let screen = screen.borrow();
+        // first paper pixel at 14336, left border 24 T-states before it
+        assert_eq!(screen.cell_t_state(BORDER_TOP * 44 + 6), 14336);
+        assert_eq!(screen.cell_t_state(BORDER_TOP * 44), 14312);
+        assert_eq!(screen.cell_t_state((BORDER_TOP + 1) * 44 + 6), 14560);
+        assert_eq!(screen.cell_t_state(0), 16 * 224 - 24);
+    }
+
+    #[test]
+    fn test_border() {
+        let (bus, _, screen) = init();
+        bus.borrow_mut().write_port(0x00FE, 0x0A);
+        screen.borrow_mut().render_frame();
+
+        let screen = screen.borrow();
+        assert_eq!(screen.framebuffer().len(), WIDTH * HEIGHT);
+        assert_eq!(screen.framebuffer()[0], PALETTE[2]);
+        assert_eq!(screen.framebuffer()[WIDTH * HEIGHT - 1], PALETTE[2]);
+        assert_eq!(screen.framebuffer()[BORDER_TOP * WIDTH], PALETTE[2]);
+        assert_eq!(pixel(&screen, 0, 0), PALETTE[0]);
+    }
+
+    #[test]
+    fn test_border_stripes() {
+        let (bus, clock, screen) = init();
+        bus.borrow_mut().write_port(0x00FE, 1);
+        at(&clock, 20 * 224);
+        bus.borrow_mut().write_port(0x00FE, 2);
+        at(&clock, 20 * 224 + 64);
+        bus.borrow_mut().write_port(0x00FE, 3);
+        screen.borrow_mut().render_frame();
+
+        // line 20 is framebuffer row 4, its left border started 24 T-states earlier
+        let screen = screen.borrow();
+        let row = |row: usize| &screen.framebuffer()[row * WIDTH..(row + 1) * WIDTH];
+        assert!(row(0).iter().all(|pixel| *pixel == PALETTE[1]));
+        assert!(row(3).iter().all(|pixel| *pixel == PALETTE[1]));
+        assert_eq!(row(3)[WIDTH - 1], PALETTE[1]);
+        assert_eq!(row(4)[0], PALETTE[1]);
+        assert_eq!(row(4)[BORDER_LEFT - 1], PALETTE[1]);
+        assert_eq!(row(4)[BORDER_LEFT], PALETTE[2]);
+        assert_eq!(row(4)[BORDER_LEFT + 127], PALETTE[2]);
+        assert_eq!(row(4)[BORDER_LEFT + 128], PALETTE[3]);
+        assert_eq!(row(HEIGHT - 1)[WIDTH - 1], PALETTE[3]);
+    }
+
+    #[test]
+    fn test_bitmap_and_attributes() {
+        let (bus, _, screen) = init();
+        bus.borrow_mut().write(0x4100, 0b10000001); // y = 1
+        bus.borrow_mut().write(0x5800, 0b01001010); // bright red ink, blue paper
+        screen.borrow_mut().render_frame();
+
+        let screen = screen.borrow();
+        assert_eq!(pixel(&screen, 0, 1), PALETTE[10]);
+        assert_eq!(pixel(&screen, 1, 1), PALETTE[9]);
+        assert_eq!(pixel(&screen, 7, 1), PALETTE[10]);
+        assert_eq!(pixel(&screen, 0, 0), PALETTE[9]);
+        assert_eq!(pixel(&screen, 8, 1), PALETTE[0]);
+    }
+
+    #[test]
+    fn test_multicolour() {
+        let (bus, clock, screen) = init();
+        bus.borrow_mut().write_vec(0x4000, vec![0xFF; 32]);
+        bus.borrow_mut().write_vec(0x4100, vec![0xFF; 32]);
+        bus.borrow_mut().write_vec(0x4200, vec![0xFF; 32]);
+
+        // change the attribute between the first and second pixel rows
+        at(&clock, 14336 + 100);
+        bus.borrow_mut().write(0x5800, 0x02);
+        at(&clock, 14336 + 224 + 100);
+        bus.borrow_mut().write(0x5800, 0x04);
+        screen.borrow_mut().render_frame();
+
+        let screen = screen.borrow();
+        assert_eq!(pixel(&screen, 0, 0), PALETTE[0]);
+        assert_eq!(pixel(&screen, 0, 1), PALETTE[2]);
+        assert_eq!(pixel(&screen, 0, 2), PALETTE[4]);
+    }
+
+    #[test]
+    fn test_observed_writes() {
+        let (bus, clock, screen) = init();
+        at(&clock, 20000);
+        bus.borrow_mut().write(0xC000, 0xFF);
+        bus.borrow_mut().write_port(0x7FFD, 0x08);
+        assert_eq!(screen.borrow().cell, 0);
+        bus.borrow_mut().write(0x4000, 0xFF);
+        assert!(screen.borrow().cell > 0);
+    }
+
+    fn init_128k() -> (Bus, RefClock, Rc<RefCell<Screen>>) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let memory = Rc::new(RefCell::new(
+            Memory128::from_bytes(&[0x00; 0x4000], &[0x00; 0x4000], Rc::clone(&clock)).unwrap()
+        ));
+        let screen = Rc::new(RefCell::new(Screen::new(memory.clone(), Rc::clone(&clock), Model::Spectrum128K)));
+        let mut bus = Bus::new();
+        bus.add_device(Box::new(Rc::clone(&memory))).unwrap();
+        bus.add_io_device(Box::new(memory));
+        bus.add_write_observer(Box::new(Rc::clone(&screen)));
+        (bus, clock, screen)
+    }
+
+    #[test]
+    fn test_observed_writes_128k() {
+        let (mut bus, clock, screen) = init_128k();
+        let cell = |screen: &Rc<RefCell<Screen>>| screen.borrow().cell;
+        // the screen frames the default clock for its model
+        assert_eq!(clock.borrow().frame_length(), Model::Spectrum128K.t_states_per_frame());
+
+        // bank 0 at 0xC000 is never displayed
+        at(&clock, 20000);
+        bus.write(0xC000, 0xFF);
+        assert_eq!(cell(&screen), 0);
+
+        // bank 5 paged at 0xC000 too
+        bus.write_port(0x7FFD, 0x05);
+        bus.write(0xC000, 0xFF);
+        let drawn = cell(&screen);
+        assert!(drawn > 0);
+
+        // the shadow screen: only bank 7 is observed
+        at(&clock, 30000);
+        bus.write_port(0x7FFD, 0x0F);
+        assert!(cell(&screen) > drawn);
+        at(&clock, 40000);
+        let drawn = cell(&screen);
+        bus.write(0x4000, 0xFF);
+        assert_eq!(cell(&screen), drawn);
+        bus.write(0xC000, 0xFF);
+        assert!(cell(&screen) > drawn);
+    }
+
+    #[test]
+    fn test_shadow_screen_switch() {
+        let (mut bus, clock, screen) = init_128k();
+        // white paper on both screens, the shadow one with ink on rows 0 and 8
+        bus.write_port(0x7FFD, 0x07);
+        bus.write_vec(0x5800, vec![0x38; 64]);
+        bus.write_vec(0xD800, vec![0x38; 64]);
+        bus.write_vec(0xC000 + pixel_offset(0, 0), vec![0xFF]);
+        bus.write_vec(0xC000 + pixel_offset(8, 0), vec![0xFF]);
+
+        // switch once the beam has drawn pixel row 0 but not row 8
+        at(&clock, 14336 + 4 * 228);
+        bus.write_port(0x7FFD, 0x0F);
+        screen.borrow_mut().render_frame();
+
+        let screen = screen.borrow();
+        assert_eq!(pixel(&screen, 0, 0), PALETTE[7]);
+        assert_eq!(pixel(&screen, 0, 8), PALETTE[0]);
+    }
+
+    #[test]
+    fn test_update_follows_clock() {
+        let (_, clock, screen) = init();
+        screen.borrow_mut().set_border(4);
+        at(&clock, 69888 + 10);
+        screen.borrow_mut().update();
+        assert_eq!(screen.borrow().frames(), 1);
+        assert_eq!(screen.borrow().framebuffer()[0], PALETTE[4]);
+
+        clock.borrow_mut().reset();
+        screen.borrow_mut().set_border(1);
+        at(&clock, 69888);
+        screen.borrow_mut().update();
+        assert_eq!(screen.borrow().frames(), 2);
+        assert_eq!(screen.borrow().framebuffer()[0], PALETTE[1]);
+    }
+
+    #[test]
+    fn test_flash() {
+        let (bus, _, screen) = init();
+        bus.borrow_mut().write_vec(0x4000, vec![0xFF]);
+        bus.borrow_mut().write_vec(0x5800, vec![0b10111000]); // flash, white paper, black ink
+
+        for frame in 0..33 {
+            screen.borrow_mut().render_frame();
+            let expected = if (frame / 16) % 2 == 0 { PALETTE[0] } else { PALETTE[7] };
+            assert_eq!(pixel(&screen.borrow(), 0, 0), expected, "frame {}", frame);
+        }
+        assert_eq!(screen.borrow().frames(), 33);
+    }
+}
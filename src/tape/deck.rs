@@ -0,0 +1,376 @@
+use std::{fs, path::Path};
+
+use crate::{clock::Event, cpu::RefClock, device::keyboard::EarSource, model::Model};
+
+use super::{signal::{Signal, TapeBlock, SIGNAL_HZ}, tap::Tap, tzx};
+
+/// A tape player feeding the EAR input. While playing, the end of every
+/// pulse is a `TapeEdge` event on the clock; the deck catches up with the
+/// events due whenever the keyboard reads EAR or `update` is called, so
+/// loaders see the signal as it would be at the time they sample it.
+/// Signal lengths are 3.5 MHz T-states, scaled to the model's clock.
+pub struct TapeDeck {
+    model: Model,
+    clock: RefClock,
+    blocks: Vec<TapeBlock>,
+    block: usize,
+    signal: usize,
+    level: bool,
+    playing: bool,
+}
+
+impl TapeDeck {
+    pub fn new(model: Model, clock: RefClock) -> Self {
+        Self {
+            model,
+            clock,
+            blocks: vec![],
+            block: 0,
+            signal: 0,
+            level: false,
+            playing: false,
+        }
+    }
+
+    /// Inserts a tape, rewound and stopped.
+    pub fn insert(&mut self, blocks: Vec<TapeBlock>) {
+        self.blocks = blocks;
+        self.rewind();
+    }
+
+    pub fn insert_tap(&mut self, tap: &Tap) {
+        self.insert(tap.blocks().iter().map(TapeBlock::from).collect());
+    }
+
+    /// Inserts a TAP or TZX file, told apart by the TZX signature.
+    pub fn insert_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let image = fs::read(path.as_ref())
+            .map_err(|error| format!("Cannot read tape {}: {}", path.as_ref().display(), error))?;
+        let blocks = if image.starts_with(b"ZXTape!") {
+            tzx::parse(&image)?
+        } else {
+            Tap::parse(&image)?.blocks().iter().map(TapeBlock::from).collect()
+        };
+        self.insert(blocks);
+        Ok(())
+    }
+
+    pub fn play(&mut self) {
+        if self.playing || self.block >= self.blocks.len() {
+            return;
+        }
+        self.playing = true;
+        let now = self.clock.borrow().read();
+        self.begin_signal(now);
+    }
+
+    /// Stops the tape. Playing again restarts the interrupted pulse.
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.clock.borrow_mut().cancel(Event::TapeEdge);
+    }
+
+    pub fn rewind(&mut self) {
+        self.seek(0);
+    }
+
+    /// Stops the tape at the start of `block`.
+    pub fn seek(&mut self, block: usize) {
+        self.stop();
+        self.block = block.min(self.blocks.len());
+        self.signal = 0;
+        self.level = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Block being played, or the block count at the end of the tape.
+    pub fn position(&self) -> usize {
+        self.block
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn description(&self, block: usize) -> Option<&str> {
+        self.blocks.get(block).map(|block| block.description.as_str())
+    }
+
+    /// Level of the signal on EAR.
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// Processes every edge due by now.
+    pub fn update(&mut self) {
+        if !self.playing {
+            return;
+        }
+
+        if self.clock.borrow().next_due(Event::TapeEdge).is_none() {
+            // The clock was reset and lost the event: carry on from now.
+            let now = self.clock.borrow().read();
+            self.begin_signal(now);
+        }
+
+        loop {
+            let due = self.clock.borrow_mut().pop_due_event(Event::TapeEdge);
+            let Some(at) = due else {
+                break;
+            };
+
+            if let Some(Signal::Pulse(_)) = self.current() {
+                self.level = !self.level;
+            }
+            self.advance();
+            if self.playing {
+                self.begin_signal(at);
+            }
+        }
+    }
+
+    fn current(&self) -> Option<Signal> {
+        self.blocks.get(self.block)?.signals.get(self.signal).copied()
+    }
+
+    fn advance(&mut self) {
+        self.signal += 1;
+        self.skip_finished_blocks();
+    }
+
+    /// Moves past the current block once its signals are over, and past any
+    /// following blocks with no signal.
+    fn skip_finished_blocks(&mut self) {
+        while self.block < self.blocks.len() && self.signal >= self.blocks[self.block].signals.len() {
+            self.block += 1;
+            self.signal = 0;
+        }
+    }
+
+    /// Starts the current signal at cycle `at`, skipping blocks without
+    /// signal, applying level changes and stopping at the end of the tape or
+    /// at a stop signal.
+    fn begin_signal(&mut self, at: u64) {
+        self.skip_finished_blocks();
+
+        loop {
+            match self.current() {
+                Some(Signal::Pulse(length)) => self.clock.borrow_mut().schedule(at + self.scale(length), Event::TapeEdge),
+                Some(Signal::Pause(length)) => {
+                    self.level = false;
+                    self.clock.borrow_mut().schedule(at + self.scale(length), Event::TapeEdge);
+                }
+                Some(Signal::Level(level)) => {
+                    self.level = level;
+                    self.advance();
+                    continue;
+                }
+                Some(Signal::Stop48K) if self.model != Model::Spectrum48K => {
+                    self.advance();
+                    continue;
+                }
+                Some(Signal::Stop | Signal::Stop48K) => {
+                    self.advance();
+                    self.stop();
+                }
+                None => self.stop(),
+            }
+            break;
+        }
+    }
+
+    /// Length in T-states of the model's clock, rounded to the nearest.
+    fn scale(&self, length: u32) -> u64 {
+        let hz = self.model.cpu_hz() as u64;
+        (length as u64 * hz + SIGNAL_HZ as u64 / 2) / SIGNAL_HZ as u64
+    }
+}
+
+/// Connected to the keyboard, which shows EAR on bit 6 of port 0xFE.
+impl EarSource for TapeDeck {
+    fn ear_level(&mut self) -> bool {
+        self.update();
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod test_deck {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{bus::Bus, clock::Clock, cpu::RefClock, device::keyboard::{EarSource, Keyboard}, model::Model, tape::signal::{Signal, TapeBlock}};
+
+    use super::TapeDeck;
+
+    fn block(signals: Vec<Signal>) -> TapeBlock {
+        TapeBlock { description: format!("{} signals", signals.len()), signals }
+    }
+
+    fn init(blocks: Vec<TapeBlock>) -> (TapeDeck, RefClock) {
+        init_model(Model::Spectrum48K, blocks)
+    }
+
+    fn init_model(model: Model, blocks: Vec<TapeBlock>) -> (TapeDeck, RefClock) {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let mut deck = TapeDeck::new(model, clock.clone());
+        deck.insert(blocks);
+        (deck, clock)
+    }
+
+    fn level_at(deck: &mut TapeDeck, clock: &RefClock, t_state: u64) -> bool {
+        let now = clock.borrow().read();
+        clock.borrow_mut().add((t_state - now) as u32);
+        deck.ear_level()
+    }
+
+    #[test]
+    fn test_pulses() {
+        let (mut deck, clock) = init(vec![
+            block(vec![Signal::Pulse(100); 3]),
+            block(vec![Signal::Pause(1000), Signal::Pulse(100)]),
+        ]);
+        assert!(!level_at(&mut deck, &clock, 10));
+
+        // playing from 10: edges at 110, 210 and 310
+        deck.play();
+        assert!(!level_at(&mut deck, &clock, 50));
+        assert!(level_at(&mut deck, &clock, 150));
+        assert!(!level_at(&mut deck, &clock, 250));
+        assert_eq!(deck.position(), 0);
+
+        // the pause pulls the level low, then the last pulse flips it
+        assert!(!level_at(&mut deck, &clock, 310));
+        assert_eq!(deck.position(), 1);
+        assert!(!level_at(&mut deck, &clock, 1309));
+        assert!(!level_at(&mut deck, &clock, 1409));
+        assert!(level_at(&mut deck, &clock, 1410));
+        assert!(!deck.is_playing());
+        assert_eq!(deck.position(), 2);
+    }
+
+    #[test]
+    fn test_empty_blocks() {
+        let (mut deck, clock) = init(vec![
+            block(vec![]),
+            block(vec![Signal::Pulse(100)]),
+            block(vec![]),
+            block(vec![]),
+            block(vec![Signal::Pulse(100)]),
+        ]);
+        // playing from an empty block starts at the next one
+        deck.play();
+        assert!(deck.is_playing());
+        assert_eq!(deck.position(), 1);
+
+        // the empty blocks in the middle are skipped at the edge
+        assert!(level_at(&mut deck, &clock, 100));
+        assert_eq!(deck.position(), 4);
+        assert!(!level_at(&mut deck, &clock, 200));
+        assert!(!deck.is_playing());
+        assert_eq!(deck.position(), 5);
+
+        deck.seek(2);
+        deck.play();
+        assert_eq!(deck.position(), 4);
+        assert!(level_at(&mut deck, &clock, 300));
+    }
+
+    #[test]
+    fn test_level() {
+        let (mut deck, clock) = init(vec![
+            block(vec![Signal::Level(true), Signal::Pulse(100), Signal::Level(false)]),
+            block(vec![Signal::Level(true)]),
+            block(vec![Signal::Pulse(100)]),
+        ]);
+        deck.play();
+        assert!(level_at(&mut deck, &clock, 99));
+        // the edge at 100 flips the level low, then the levels set it high
+        assert!(level_at(&mut deck, &clock, 100));
+        assert!(!level_at(&mut deck, &clock, 200));
+        assert!(!deck.is_playing());
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let (mut deck, clock) = init(vec![block(vec![Signal::Pulse(855); 1001])]);
+        deck.play();
+        // edges are scheduled from the previous edge, not from the late read
+        assert!(level_at(&mut deck, &clock, 855 * 1000 - 1));
+        assert!(!level_at(&mut deck, &clock, 855 * 1001 - 1));
+        assert!(level_at(&mut deck, &clock, 855 * 1001));
+    }
+
+    #[test]
+    fn test_controls() {
+        let (mut deck, clock) = init(vec![
+            block(vec![Signal::Pulse(100)]),
+            block(vec![]),
+            block(vec![Signal::Stop]),
+            block(vec![Signal::Pulse(100)]),
+        ]);
+        assert_eq!(deck.block_count(), 4);
+        assert_eq!(deck.description(0), Some("1 signals"));
+
+        deck.play();
+        level_at(&mut deck, &clock, 50);
+        deck.stop();
+        assert!(!level_at(&mut deck, &clock, 500));
+
+        // the interrupted pulse plays again, then the stop block stops the tape
+        deck.play();
+        assert!(level_at(&mut deck, &clock, 600));
+        assert!(!deck.is_playing());
+        assert_eq!(deck.position(), 3);
+
+        deck.play();
+        assert!(!level_at(&mut deck, &clock, 700));
+        assert_eq!(deck.position(), 4);
+
+        deck.rewind();
+        assert_eq!(deck.position(), 0);
+        assert!(!deck.is_playing());
+        assert!(!deck.level());
+    }
+
+    #[test]
+    fn test_128k() {
+        let blocks = vec![
+            block(vec![Signal::Pulse(3500)]),
+            block(vec![Signal::Stop48K]),
+            block(vec![Signal::Pulse(100)]),
+        ];
+        // 3500 T-states at 3.5 MHz last 3547 at 3.5469 MHz, and 48K stops
+        // are played through
+        let (mut deck, clock) = init_model(Model::Spectrum128K, blocks.clone());
+        deck.play();
+        assert!(!level_at(&mut deck, &clock, 3546));
+        assert!(level_at(&mut deck, &clock, 3547));
+        assert!(deck.is_playing());
+        assert_eq!(deck.position(), 2);
+
+        let (mut deck, clock) = init(blocks);
+        deck.play();
+        assert!(level_at(&mut deck, &clock, 3500));
+        assert!(!deck.is_playing());
+        assert_eq!(deck.position(), 2);
+    }
+
+    #[test]
+    fn test_with_keyboard() {
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        let deck = Rc::new(RefCell::new(TapeDeck::new(Model::Spectrum48K, clock.clone())));
+        let keyboard = Rc::new(RefCell::new(Keyboard::new()));
+        let mut bus = Bus::new();
+        keyboard.borrow_mut().connect_ear(Box::new(Rc::clone(&deck)));
+        bus.add_io_device(Box::new(Rc::clone(&keyboard)));
+
+        deck.borrow_mut().insert(vec![block(vec![Signal::Pulse(100), Signal::Pulse(100)])]);
+        deck.borrow_mut().play();
+        assert_eq!(bus.read_port(0x7FFE), 0xBF);
+        clock.borrow_mut().add(150);
+        assert_eq!(bus.read_port(0x7FFE), 0xFF);
+    }
+}
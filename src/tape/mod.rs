@@ -0,0 +1,4 @@
+pub mod deck;
+pub mod signal;
+pub mod tap;
+pub mod tzx;
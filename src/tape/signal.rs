@@ -0,0 +1,215 @@
+use super::tap::TapBlock;
+
+/// Clock the signal lengths are given in, the 48K's.
+pub const SIGNAL_HZ: u32 = 3_500_000;
+
+/// ROM loader timings, in T-states at 3.5 MHz.
+pub const PILOT_PULSE: u32 = 2168;
+pub const HEADER_PILOT_PULSES: u32 = 8063;
+pub const DATA_PILOT_PULSES: u32 = 3223;
+pub const SYNC1_PULSE: u32 = 667;
+pub const SYNC2_PULSE: u32 = 735;
+pub const ZERO_PULSE: u32 = 855;
+pub const ONE_PULSE: u32 = 1710;
+pub const T_STATES_PER_MS: u32 = 3500;
+
+/// A stretch of the tape signal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    /// Keeps the level for the given T-states, then flips it.
+    Pulse(u32),
+    /// Silence: the level goes low for the given T-states.
+    Pause(u32),
+    /// Sets the level at once, as a TZX "set signal level" block does.
+    Level(bool),
+    /// The tape stops here, as a TZX "stop the tape" block asks.
+    Stop,
+    /// The tape stops here only on a 48K.
+    Stop48K,
+}
+
+/// A block of a tape as a signal, with a description to show the user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TapeBlock {
+    pub description: String,
+    pub signals: Vec<Signal>,
+}
+
+/// Timings of a block saved in the ROM format, possibly with turbo timings.
+pub struct DataTimings {
+    pub pilot: u32,
+    pub pilot_pulses: u32,
+    pub sync1: u32,
+    pub sync2: u32,
+    pub zero: u32,
+    pub one: u32,
+    /// Bits used in the last byte, 8 for whole bytes.
+    pub last_bits: u8,
+    pub pause_ms: u32,
+}
+
+impl DataTimings {
+    /// ROM timings for a block starting with `flag`.
+    pub fn standard(flag: u8, pause_ms: u32) -> Self {
+        Self {
+            pilot: PILOT_PULSE,
+            pilot_pulses: if flag < 0x80 { HEADER_PILOT_PULSES } else { DATA_PILOT_PULSES },
+            sync1: SYNC1_PULSE,
+            sync2: SYNC2_PULSE,
+            zero: ZERO_PULSE,
+            one: ONE_PULSE,
+            last_bits: 8,
+            pause_ms,
+        }
+    }
+}
+
+/// Pilot tone, sync pulses, data and the pause after them. Turbo blocks with
+/// no pilot or sync leave those counts or lengths at 0.
+pub fn data_signals(bytes: &[u8], timings: &DataTimings) -> Vec<Signal> {
+    let mut signals = vec![Signal::Pulse(timings.pilot); timings.pilot_pulses as usize];
+    if timings.sync1 > 0 {
+        signals.push(Signal::Pulse(timings.sync1));
+    }
+    if timings.sync2 > 0 {
+        signals.push(Signal::Pulse(timings.sync2));
+    }
+    signals.extend(pure_data_signals(bytes, timings.zero, timings.one, timings.last_bits));
+    if timings.pause_ms > 0 {
+        signals.push(Signal::Pause(timings.pause_ms * T_STATES_PER_MS));
+    }
+    signals
+}
+
+/// Two pulses per bit, most significant bit first.
+pub fn pure_data_signals(bytes: &[u8], zero: u32, one: u32, last_bits: u8) -> Vec<Signal> {
+    let mut signals = Vec::with_capacity(bytes.len() * 16);
+    for (i, byte) in bytes.iter().enumerate() {
+        let bits = if i == bytes.len() - 1 { last_bits.clamp(1, 8) } else { 8 };
+        for bit in 0..bits {
+            let length = if byte & (0x80 >> bit) != 0 { one } else { zero };
+            signals.push(Signal::Pulse(length));
+            signals.push(Signal::Pulse(length));
+        }
+    }
+    signals
+}
+
+/// A direct recording: one sample per bit, most significant bit first, each
+/// lasting `sample` T-states. Runs of equal samples become pulses, and the
+/// level of the last run is kept after its closing edge.
+pub fn direct_signals(bytes: &[u8], sample: u32, last_bits: u8, pause_ms: u32) -> Vec<Signal> {
+    let mut levels = vec![];
+    for (i, byte) in bytes.iter().enumerate() {
+        let bits = if i == bytes.len() - 1 { last_bits.clamp(1, 8) } else { 8 };
+        levels.extend((0..bits).map(|bit| byte & (0x80 >> bit) != 0));
+    }
+
+    let mut signals = vec![];
+    let mut samples = levels.iter().peekable();
+    while let Some(&level) = samples.next() {
+        if signals.is_empty() {
+            signals.push(Signal::Level(level));
+        }
+        let mut run = 1;
+        while samples.next_if_eq(&&level).is_some() {
+            run += 1;
+        }
+        signals.push(Signal::Pulse(run * sample));
+        if samples.peek().is_none() {
+            signals.push(Signal::Level(level));
+        }
+    }
+    if pause_ms > 0 {
+        signals.push(Signal::Pause(pause_ms * T_STATES_PER_MS));
+    }
+    signals
+}
+
+/// Headers show the file type and name, like the ROM does when loading.
+pub fn describe(bytes: &[u8]) -> String {
+    match bytes {
+        [0x00, kind, name @ ..] if bytes.len() == 19 => {
+            let kind = match kind {
+                0 => "Program",
+                1 => "Number array",
+                2 => "Character array",
+                _ => "Bytes",
+            };
+            let name: String = name[..10].iter().map(|c| if c.is_ascii_graphic() || *c == b' ' { *c as char } else { '?' }).collect();
+            format!("{}: {}", kind, name.trim_end())
+        }
+        [flag, ..] => format!("Data block (flag {:#04X}, {} bytes)", flag, bytes.len().saturating_sub(2)),
+        [] => "Empty block".to_string(),
+    }
+}
+
+impl From<&TapBlock> for TapeBlock {
+    fn from(block: &TapBlock) -> Self {
+        Self {
+            description: describe(block.bytes()),
+            signals: data_signals(block.bytes(), &DataTimings::standard(block.flag(), 1000)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_signal {
+    use crate::tape::tap::Tap;
+
+    use super::{describe, direct_signals, pure_data_signals, Signal, TapeBlock, DATA_PILOT_PULSES, HEADER_PILOT_PULSES};
+
+    #[test]
+    fn test_bits() {
+        assert_eq!(
+            pure_data_signals(&[0x80, 0x40], 1, 2, 2),
+            vec![
+                Signal::Pulse(2), Signal::Pulse(2),
+                Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1),
+                Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1),
+                Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(1),
+                Signal::Pulse(1), Signal::Pulse(1),
+                Signal::Pulse(1), Signal::Pulse(1), Signal::Pulse(2), Signal::Pulse(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_direct() {
+        assert_eq!(
+            direct_signals(&[0b11000111, 0b00000000], 10, 1, 0),
+            vec![
+                Signal::Level(true), Signal::Pulse(20), Signal::Pulse(30),
+                Signal::Pulse(30), Signal::Pulse(10), Signal::Level(false),
+            ]
+        );
+        assert_eq!(
+            direct_signals(&[0x00], 1, 8, 1),
+            vec![Signal::Level(false), Signal::Pulse(8), Signal::Level(false), Signal::Pause(3500)]
+        );
+    }
+
+    #[test]
+    fn test_tap_block() {
+        let mut header = vec![0x00, 0x03];
+        header.extend(b"screen    ");
+        header.extend([0x00, 0x1B, 0x00, 0x40, 0x00, 0x80]);
+        header.push(header.iter().fold(0, |parity, byte| parity ^ byte));
+        let mut image = vec![header.len() as u8, 0x00];
+        image.extend(&header);
+        image.extend([0x03, 0x00, 0xFF, 0x01, 0xFE]);
+        let tap = Tap::parse(&image).unwrap();
+
+        let block = TapeBlock::from(&tap.blocks()[0]);
+        assert_eq!(block.description, "Bytes: screen");
+        let pilot = HEADER_PILOT_PULSES as usize;
+        assert_eq!(block.signals.len(), pilot + 2 + 19 * 16 + 1);
+        assert_eq!(block.signals[pilot], Signal::Pulse(667));
+        assert_eq!(block.signals[pilot + 1], Signal::Pulse(735));
+        assert_eq!(block.signals.last(), Some(&Signal::Pause(3_500_000)));
+
+        let block = TapeBlock::from(&tap.blocks()[1]);
+        assert_eq!(block.signals.len(), DATA_PILOT_PULSES as usize + 2 + 3 * 16 + 1);
+        assert_eq!(describe(&[0xFF, 0x01, 0xFE]), "Data block (flag 0xFF, 1 bytes)");
+    }
+}
@@ -0,0 +1,133 @@
+use std::{fs, path::Path};
+
+/// A block of a TAP file: the flag byte, the data and the checksum, as the
+/// ROM saves them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TapBlock {
+    bytes: Vec<u8>,
+}
+
+impl TapBlock {
+    /// Flag, data and checksum.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// 0x00 for headers, 0xFF for data blocks.
+    pub fn flag(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[1..self.bytes.len() - 1]
+    }
+
+    pub fn checksum(&self) -> u8 {
+        self.bytes[self.bytes.len() - 1]
+    }
+
+    /// The checksum is the XOR of the flag and the data, so XORing the whole
+    /// block gives 0.
+    pub fn is_valid(&self) -> bool {
+        self.bytes.iter().fold(0, |parity, byte| parity ^ byte) == 0
+    }
+}
+
+/// A TAP image: blocks stored one after the other, each preceded by its
+/// length as a little-endian word.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tap {
+    blocks: Vec<TapBlock>,
+}
+
+impl Tap {
+    pub fn parse(image: &[u8]) -> Result<Self, String> {
+        let mut blocks = vec![];
+        let mut offset = 0;
+
+        while offset < image.len() {
+            let number = blocks.len();
+            if image.len() - offset < 2 {
+                return Err(format!("Block {} truncated at offset {:#06X}: missing length", number, offset));
+            }
+
+            let length = u16::from_le_bytes([image[offset], image[offset + 1]]) as usize;
+            let start = offset + 2;
+            if length < 2 {
+                return Err(format!("Block {} at offset {:#06X} is {} bytes long, too short for flag and checksum", number, offset, length));
+            }
+            if image.len() - start < length {
+                return Err(format!(
+                    "Block {} truncated at offset {:#06X}: expected {} bytes, found {}",
+                    number, offset, length, image.len() - start
+                ));
+            }
+
+            blocks.push(TapBlock { bytes: image[start..start + length].to_vec() });
+            offset = start + length;
+        }
+
+        Ok(Self { blocks })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let image = fs::read(path.as_ref())
+            .map_err(|error| format!("Cannot read tape {}: {}", path.as_ref().display(), error))?;
+        Self::parse(&image)
+    }
+
+    pub fn blocks(&self) -> &[TapBlock] {
+        &self.blocks
+    }
+
+    /// Checks every block checksum.
+    pub fn verify(&self) -> Result<(), String> {
+        match self.blocks.iter().position(|block| !block.is_valid()) {
+            Some(number) => Err(format!("Block {} has a bad checksum", number)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tap {
+    use super::Tap;
+
+    #[test]
+    fn test_parse() {
+        let tap = Tap::parse(&[
+            0x04, 0x00, 0x00, 0x01, 0x02, 0x03,
+            0x03, 0x00, 0xFF, 0xAA, 0x55,
+        ]).unwrap();
+        assert_eq!(tap.blocks().len(), 2);
+        assert_eq!(tap.blocks()[0].flag(), 0x00);
+        assert_eq!(tap.blocks()[0].data(), &[0x01, 0x02]);
+        assert_eq!(tap.blocks()[0].checksum(), 0x03);
+        assert!(tap.blocks()[0].is_valid());
+        assert_eq!(tap.blocks()[1].data(), &[0xAA]);
+        assert!(tap.blocks()[1].is_valid());
+        assert!(tap.verify().is_ok());
+
+        assert_eq!(Tap::parse(&[]).unwrap().blocks().len(), 0);
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let tap = Tap::parse(&[0x03, 0x00, 0xFF, 0xAA, 0x00]).unwrap();
+        assert!(!tap.blocks()[0].is_valid());
+        assert_eq!(tap.verify(), Err("Block 0 has a bad checksum".to_string()));
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(
+            Tap::parse(&[0x03, 0x00, 0xFF, 0xAA, 0x55, 0x13, 0x00, 0x00, 0x03]),
+            Err("Block 1 truncated at offset 0x0005: expected 19 bytes, found 2".to_string())
+        );
+        assert_eq!(
+            Tap::parse(&[0x03, 0x00, 0xFF, 0xAA, 0x55, 0x13]),
+            Err("Block 1 truncated at offset 0x0005: missing length".to_string())
+        );
+        assert!(Tap::parse(&[0x01, 0x00, 0xFF]).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use super::signal::{data_signals, describe, direct_signals, pure_data_signals, DataTimings, Signal, TapeBlock, T_STATES_PER_MS};
+
+const SIGNATURE: &[u8] = b"ZXTape!\x1A";
+
+/// Reads TZX images into tape blocks. Supports the data, tone and direct
+/// recording blocks (0x10-0x15), generalized data (0x19), pauses and stops
+/// (0x20, 0x2A), loops and groups, signal levels (0x2B) and skips the
+/// informative blocks. Other blocks of the TZX 1.20 specification are
+/// skipped too, leaving a block with no signal that says so; IDs it doesn't
+/// define are reported as unsupported, since their length is unknown.
+pub fn parse(image: &[u8]) -> Result<Vec<TapeBlock>, String> {
+    if image.len() < 10 || &image[..8] != SIGNATURE {
+        return Err("Not a TZX file".to_string());
+    }
+
+    let mut reader = Reader { image, offset: 10 };
+    let mut blocks = vec![];
+    let mut loop_start: Option<(usize, u16)> = None;
+
+    while reader.offset < image.len() {
+        let offset = reader.offset;
+        let id = reader.byte()?;
+        let block = match id {
+            0x10 => {
+                let pause = reader.word()? as u32;
+                let length = reader.word()? as usize;
+                let data = reader.bytes(length)?;
+                let flag = data.first().copied().unwrap_or(0xFF);
+                Some(TapeBlock {
+                    description: describe(data),
+                    signals: data_signals(data, &DataTimings::standard(flag, pause)),
+                })
+            }
+            0x11 => {
+                let mut timings = DataTimings {
+                    pilot: reader.word()? as u32,
+                    sync1: reader.word()? as u32,
+                    sync2: reader.word()? as u32,
+                    zero: reader.word()? as u32,
+                    one: reader.word()? as u32,
+                    pilot_pulses: reader.word()? as u32,
+                    last_bits: reader.byte()?,
+                    pause_ms: 0,
+                };
+                timings.pause_ms = reader.word()? as u32;
+                let length = reader.triple()? as usize;
+                let data = reader.bytes(length)?;
+                Some(TapeBlock {
+                    description: format!("Turbo {}", describe(data)),
+                    signals: data_signals(data, &timings),
+                })
+            }
+            0x12 => {
+                let length = reader.word()? as u32;
+                let count = reader.word()? as usize;
+                Some(TapeBlock {
+                    description: format!("Pure tone ({} pulses)", count),
+                    signals: vec![Signal::Pulse(length); count],
+                })
+            }
+            0x13 => {
+                let count = reader.byte()? as usize;
+                let signals = (0..count)
+                    .map(|_| reader.word().map(|length| Signal::Pulse(length as u32)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(TapeBlock {
+                    description: format!("Pulse sequence ({} pulses)", count),
+                    signals,
+                })
+            }
+            0x14 => {
+                let zero = reader.word()? as u32;
+                let one = reader.word()? as u32;
+                let last_bits = reader.byte()?;
+                let pause = reader.word()? as u32;
+                let length = reader.triple()? as usize;
+                let data = reader.bytes(length)?;
+                let mut signals = pure_data_signals(data, zero, one, last_bits);
+                if pause > 0 {
+                    signals.push(Signal::Pause(pause * T_STATES_PER_MS));
+                }
+                Some(TapeBlock {
+                    description: format!("Pure data ({} bytes)", length),
+                    signals,
+                })
+            }
+            0x15 => {
+                let sample = reader.word()? as u32;
+                let pause = reader.word()? as u32;
+                let last_bits = reader.byte()?;
+                let length = reader.triple()? as usize;
+                let data = reader.bytes(length)?;
+                Some(TapeBlock {
+                    description: format!("Direct recording ({} bytes)", length),
+                    signals: direct_signals(data, sample, last_bits, pause),
+                })
+            }
+            0x16..=0x18 => {
+                let length = reader.dword()? as usize;
+                reader.bytes(length)?;
+                Some(skipped(id))
+            }
+            0x19 => {
+                let length = reader.dword()? as usize;
+                let start = reader.offset;
+                reader.bytes(length)?;
+                // Parsed within its declared length.
+                let mut block = Reader { image: &image[..reader.offset], offset: start };
+                Some(generalized_data(&mut block)?)
+            }
+            0x20 => {
+                let pause = reader.word()? as u32;
+                Some(match pause {
+                    0 => TapeBlock { description: "Stop the tape".to_string(), signals: vec![Signal::Stop] },
+                    _ => TapeBlock { description: format!("Pause ({} ms)", pause), signals: vec![Signal::Pause(pause * T_STATES_PER_MS)] },
+                })
+            }
+            0x21 => {
+                let length = reader.byte()? as usize;
+                let name = String::from_utf8_lossy(reader.bytes(length)?).to_string();
+                Some(TapeBlock { description: format!("Group: {}", name), signals: vec![] })
+            }
+            0x22 => None,
+            0x23 => {
+                reader.word()?;
+                Some(skipped(id))
+            }
+            0x24 => {
+                loop_start = Some((blocks.len(), reader.word()?));
+                None
+            }
+            0x25 => {
+                let (start, repetitions) = loop_start
+                    .take()
+                    .ok_or(format!("Loop end without loop start at offset {:#06X}", offset))?;
+                let body: Vec<TapeBlock> = blocks[start..].to_vec();
+                for _ in 1..repetitions {
+                    blocks.extend(body.iter().cloned());
+                }
+                None
+            }
+            0x26 => {
+                let count = reader.word()? as usize;
+                reader.bytes(count * 2)?;
+                Some(skipped(id))
+            }
+            0x27 => Some(skipped(id)),
+            0x28 => {
+                let length = reader.word()? as usize;
+                reader.bytes(length)?;
+                Some(skipped(id))
+            }
+            0x2A => {
+                reader.bytes(4)?;
+                Some(TapeBlock { description: "Stop the tape (48K)".to_string(), signals: vec![Signal::Stop48K] })
+            }
+            0x2B => {
+                let length = reader.dword()? as usize;
+                let level = reader.bytes(length)?.first().is_some_and(|level| *level != 0);
+                Some(TapeBlock {
+                    description: format!("Set signal level {}", if level { "high" } else { "low" }),
+                    signals: vec![Signal::Level(level)],
+                })
+            }
+            0x30 => {
+                let length = reader.byte()? as usize;
+                let text = String::from_utf8_lossy(reader.bytes(length)?).to_string();
+                Some(TapeBlock { description: text, signals: vec![] })
+            }
+            0x31 => {
+                reader.byte()?;
+                let length = reader.byte()? as usize;
+                reader.bytes(length)?;
+                None
+            }
+            0x32 => {
+                let length = reader.word()? as usize;
+                reader.bytes(length)?;
+                None
+            }
+            0x33 => {
+                let count = reader.byte()? as usize;
+                reader.bytes(count * 3)?;
+                None
+            }
+            0x34 => {
+                reader.bytes(8)?;
+                None
+            }
+            0x35 => {
+                reader.bytes(16)?;
+                let length = reader.dword()? as usize;
+                reader.bytes(length)?;
+                None
+            }
+            0x40 => {
+                reader.byte()?;
+                let length = reader.triple()? as usize;
+                reader.bytes(length)?;
+                Some(skipped(id))
+            }
+            0x5A => {
+                reader.bytes(9)?;
+                None
+            }
+            _ => return Err(format!("Unsupported TZX block {:#04X} at offset {:#06X}", id, offset)),
+        };
+
+        if let Some(block) = block {
+            blocks.push(block);
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// A symbol of a generalized data block: pulse lengths, cut at the first
+/// 0, and in the low bits of `flags` what happens to the level first.
+struct Symbol {
+    flags: u8,
+    pulses: Vec<u32>,
+}
+
+/// Generalized data (0x19): a pilot of symbols repeated by run lengths,
+/// then data symbols packed in as few bits as their alphabet needs.
+fn generalized_data(reader: &mut Reader) -> Result<TapeBlock, String> {
+    let pause = reader.word()? as u32;
+    let pilot_count = reader.dword()? as usize;
+    let pilot_pulses = reader.byte()? as usize;
+    let pilot_alphabet = alphabet(reader.byte()?);
+    let data_count = reader.dword()? as usize;
+    let data_pulses = reader.byte()? as usize;
+    let data_alphabet = alphabet(reader.byte()?);
+
+    let mut signals = vec![];
+    if pilot_count > 0 {
+        let table = symbols(reader, pilot_alphabet, pilot_pulses)?;
+        for _ in 0..pilot_count {
+            let offset = reader.offset;
+            let index = reader.byte()? as usize;
+            let repetitions = reader.word()?;
+            let symbol = lookup(&table, index, offset)?;
+            for _ in 0..repetitions {
+                push_symbol(&mut signals, symbol);
+            }
+        }
+    }
+
+    if data_count > 0 {
+        let table = symbols(reader, data_alphabet, data_pulses)?;
+        let bits = (usize::BITS - (data_alphabet - 1).leading_zeros()) as usize;
+        let offset = reader.offset;
+        let stream = reader.bytes((bits * data_count).div_ceil(8))?;
+        for i in 0..data_count {
+            let index = (i * bits..(i + 1) * bits)
+                .fold(0, |index, bit| index << 1 | (stream[bit / 8] >> (7 - bit % 8) & 1) as usize);
+            push_symbol(&mut signals, lookup(&table, index, offset + i * bits / 8)?);
+        }
+    }
+
+    if pause > 0 {
+        signals.push(Signal::Pause(pause * T_STATES_PER_MS));
+    }
+    Ok(TapeBlock { description: format!("Generalized data ({} symbols)", data_count), signals })
+}
+
+/// Symbols in an alphabet, where 0 stands for 256.
+fn alphabet(size: u8) -> usize {
+    if size == 0 { 256 } else { size as usize }
+}
+
+fn symbols(reader: &mut Reader, count: usize, pulses: usize) -> Result<Vec<Symbol>, String> {
+    (0..count)
+        .map(|_| {
+            let flags = reader.byte()?;
+            let lengths = (0..pulses).map(|_| reader.word()).collect::<Result<Vec<_>, _>>()?;
+            let pulses = lengths.into_iter().take_while(|length| *length > 0).map(u32::from).collect();
+            Ok(Symbol { flags, pulses })
+        })
+        .collect()
+}
+
+fn lookup(table: &[Symbol], index: usize, offset: usize) -> Result<&Symbol, String> {
+    table.get(index).ok_or(format!("Undefined generalized data symbol {} at offset {:#06X}", index, offset))
+}
+
+/// Every pulse ends with an edge, so a symbol starting with one just adds
+/// its pulses. One keeping the level stretches the previous pulse instead,
+/// and one forcing the level sets it first.
+fn push_symbol(signals: &mut Vec<Signal>, symbol: &Symbol) {
+    let mut pulses = symbol.pulses.iter().copied();
+    match symbol.flags & 0x03 {
+        0x01 => {
+            if let (Some(Signal::Pulse(previous)), Some(first)) = (signals.last_mut(), symbol.pulses.first()) {
+                *previous += first;
+                pulses.next();
+            }
+        }
+        0x02 => signals.push(Signal::Level(false)),
+        0x03 => signals.push(Signal::Level(true)),
+        _ => {}
+    }
+    signals.extend(pulses.map(Signal::Pulse));
+}
+
+fn skipped(id: u8) -> TapeBlock {
+    TapeBlock { description: format!("Unsupported block {:#04X} skipped", id), signals: vec![] }
+}
+
+struct Reader<'a> {
+    image: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.image.len() - self.offset < count {
+            return Err(format!(
+                "TZX truncated at offset {:#06X}: expected {} bytes, found {}",
+                self.offset, count, self.image.len() - self.offset
+            ));
+        }
+        self.offset += count;
+        Ok(&self.image[self.offset - count..self.offset])
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn triple(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    }
+
+    fn dword(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod test_tzx {
+    use crate::tape::signal::Signal;
+
+    use super::parse;
+
+    fn image(blocks: &[u8]) -> Vec<u8> {
+        let mut image = b"ZXTape!\x1A\x01\x14".to_vec();
+        image.extend(blocks);
+        image
+    }
+
+    #[test]
+    fn test_blocks() {
+        let blocks = parse(&image(&[
+            0x10, 0xE8, 0x03, 0x03, 0x00, 0xFF, 0x01, 0xFE,
+            0x12, 0x00, 0x01, 0x03, 0x00,
+            0x13, 0x02, 0x10, 0x00, 0x20, 0x00,
+            0x30, 0x02, b'h', b'i',
+            0x14, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x80,
+            0x20, 0x00, 0x00,
+        ])).unwrap();
+
+        assert_eq!(blocks.len(), 6);
+        assert_eq!(blocks[0].signals.last(), Some(&Signal::Pause(3_500_000)));
+        assert_eq!(blocks[1].signals, vec![Signal::Pulse(256); 3]);
+        assert_eq!(blocks[2].signals, vec![Signal::Pulse(16), Signal::Pulse(32)]);
+        assert_eq!(blocks[3].description, "hi");
+        assert_eq!(blocks[4].signals, vec![Signal::Pulse(2), Signal::Pulse(2)]);
+        assert_eq!(blocks[5].signals, vec![Signal::Stop]);
+    }
+
+    #[test]
+    fn test_loop() {
+        let blocks = parse(&image(&[
+            0x24, 0x03, 0x00,
+            0x12, 0x10, 0x00, 0x01, 0x00,
+            0x25,
+        ])).unwrap();
+        assert_eq!(blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(b"ZXTapf!\x1A\x01\x14"), Err("Not a TZX file".to_string()));
+        assert_eq!(
+            parse(&image(&[0x10, 0xE8, 0x03, 0x05, 0x00, 0xFF])),
+            Err("TZX truncated at offset 0x000F: expected 5 bytes, found 1".to_string())
+        );
+        assert_eq!(
+            parse(&image(&[0x7F, 0x00, 0x00, 0x00, 0x00])),
+            Err("Unsupported TZX block 0x7F at offset 0x000A".to_string())
+        );
+        assert_eq!(
+            parse(&image(&[0x2B, 0x01, 0x00, 0x00])),
+            Err("TZX truncated at offset 0x000B: expected 4 bytes, found 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_skipped() {
+        let blocks = parse(&image(&[
+            0x15, 0x4F, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0xF0,
+            0x18, 0x02, 0x00, 0x00, 0x00, 0xAA, 0xBB,
+            0x23, 0x01, 0x00,
+            0x26, 0x01, 0x00, 0x02, 0x00,
+            0x27,
+            0x28, 0x01, 0x00, 0x00,
+            0x2B, 0x01, 0x00, 0x00, 0x00, 0x01,
+            0x34, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x40, 0x00, 0x03, 0x00, 0x00, 0x01, 0x02, 0x03,
+            0x12, 0x10, 0x00, 0x01, 0x00,
+        ])).unwrap();
+
+        assert_eq!(blocks.len(), 9);
+        assert_eq!(blocks[0].description, "Direct recording (1 bytes)");
+        assert_eq!(blocks[0].signals, vec![Signal::Level(true), Signal::Pulse(316), Signal::Level(true)]);
+        assert_eq!(blocks[1].description, "Unsupported block 0x18 skipped");
+        assert!(blocks[1].signals.is_empty());
+        assert_eq!(blocks[5].description, "Unsupported block 0x28 skipped");
+        assert_eq!(blocks[6].signals, vec![Signal::Level(true)]);
+        assert_eq!(blocks[7].description, "Unsupported block 0x40 skipped");
+        assert_eq!(blocks[8].signals, vec![Signal::Pulse(16)]);
+    }
+
+    #[test]
+    fn test_generalized_data() {
+        let blocks = parse(&image(&[
+            0x19, 0x21, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x02, 0x01,
+            0x03, 0x00, 0x00, 0x00, 0x02, 0x02,
+            // pilot: forced high, one pulse cut by the 0, three times
+            0x03, 0x64, 0x00, 0x00, 0x00,
+            0x00, 0x03, 0x00,
+            // data: 0 starts with an edge, 1 keeps the level
+            0x00, 0x0A, 0x00, 0x0A, 0x00,
+            0x01, 0x14, 0x00, 0x14, 0x00,
+            0b01000000,
+            0x12, 0x10, 0x00, 0x01, 0x00,
+        ])).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].description, "Generalized data (3 symbols)");
+        assert_eq!(
+            blocks[0].signals,
+            vec![
+                Signal::Level(true), Signal::Pulse(100), Signal::Level(true), Signal::Pulse(100),
+                Signal::Level(true), Signal::Pulse(100),
+                Signal::Pulse(10), Signal::Pulse(30), Signal::Pulse(20), Signal::Pulse(10), Signal::Pulse(10),
+            ]
+        );
+        assert_eq!(blocks[1].signals, vec![Signal::Pulse(16)]);
+
+        // symbols are read within the block's declared length
+        assert_eq!(
+            parse(&image(&[0x19, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x10, 0x00, 0x01, 0x00])),
+            Err("TZX truncated at offset 0x0011: expected 4 bytes, found 0".to_string())
+        );
+        assert_eq!(
+            parse(&image(&[
+                0x19, 0x12, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+                0x00,
+                0x05, 0x01, 0x00,
+            ])),
+            Err("Undefined generalized data symbol 5 at offset 0x001E".to_string())
+        );
+    }
+}